@@ -0,0 +1,93 @@
+use std::future::{ready, Ready};
+
+use actix_web::dev::Payload;
+use actix_web::http::header;
+use actix_web::web::Data;
+use actix_web::{FromRequest, HttpRequest};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+use crate::config::AdminConfig;
+use crate::errors::CustomError;
+
+/// Allows to call an admin endpoint if the client's address is allowed and its credentials are
+/// valid (see `AdminConfig`)
+pub struct AdminAuth;
+
+impl FromRequest for AdminAuth {
+    type Error = CustomError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authorize(req).map(|_| AdminAuth))
+    }
+}
+
+/// Like `AdminAuth`, but also refuses access if neither credentials nor allowed addresses are
+/// configured since the endpoint modifies data
+pub struct AdminApiAuth;
+
+impl FromRequest for AdminApiAuth {
+    type Error = CustomError;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(authorize_admin_api(req).map(|_| AdminApiAuth))
+    }
+}
+
+/// Checks access the same way as `AdminApiAuth`; is used when only some parameters of a public
+/// endpoint require admin access
+pub fn authorize_admin_api(req: &HttpRequest) -> Result<(), CustomError> {
+    let config = get_config(req)?;
+    if config.username.is_empty() && config.allowed_ips.is_empty() {
+        return Err(CustomError::Forbidden {
+            message: String::from("Admin API is disabled since admin access isn't restricted"),
+        });
+    }
+    authorize(req)
+}
+
+fn get_config(req: &HttpRequest) -> Result<&Data<AdminConfig>, CustomError> {
+    req.app_data::<Data<AdminConfig>>()
+        .ok_or(CustomError::InternalError)
+}
+
+fn authorize(req: &HttpRequest) -> Result<(), CustomError> {
+    let config = get_config(req)?;
+
+    // the address of the connection is used since headers like X-Forwarded-For can be forged
+    if !config.allowed_ips.is_empty() {
+        let is_allowed = req
+            .peer_addr()
+            .is_some_and(|addr| config.allowed_ips.contains(&addr.ip()));
+        if !is_allowed {
+            return Err(CustomError::Forbidden {
+                message: String::from("Admin endpoints can't be accessed from this address"),
+            });
+        }
+    }
+
+    if !config.username.is_empty() {
+        let credentials = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|value| STANDARD.decode(value.trim()).ok())
+            .unwrap_or_default();
+        let expected = format!("{}:{}", config.username, config.password);
+        if !constant_time_eq(&credentials, expected.as_bytes()) {
+            return Err(CustomError::Unauthorized {
+                message: String::from("Valid admin credentials are required"),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+// the time of comparison shouldn't reveal how many characters of a password are guessed
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use mongodb::bson::{self, Bson};
+use tokio_stream::StreamExt;
+
+use mongodb_redis::config::AppConfig;
+use mongodb_redis::db::MongoDbClient;
+use mongodb_redis::errors::CustomError;
+use mongodb_redis::model::{Planet, PlanetType};
+use mongodb_redis::redis::{self, StreamPosition};
+use mongodb_redis::services::{EventPublishing, PlanetService};
+
+// revisions of planets changed by the tool are attributed to this actor
+const ACTOR: &str = "cli";
+const DEFAULT_SEED_FILE: &str = "mongodb-init/init.json";
+
+/// Manages data of mongodb-redis service; the configuration is loaded the same way as by the service
+#[derive(Parser)]
+#[command(name = "mongodb-redis-cli")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Creates planets from a JSON array (MongoDB Extended JSON is supported) or updates planets
+    /// with the same names
+    Seed {
+        #[arg(default_value = DEFAULT_SEED_FILE)]
+        file: PathBuf,
+    },
+    /// Lists planets
+    List {
+        #[arg(long = "type", value_parser = parse_planet_type)]
+        planet_type: Option<PlanetType>,
+        #[arg(long)]
+        include_deleted: bool,
+    },
+    /// Checks stored planets; fails if any of them is invalid
+    Validate,
+    /// Caches all planets and their statistics
+    WarmCache,
+    /// Deletes cached planets and statistics or only entries with the prefix, e.g. `planet:{id}`
+    FlushCache {
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+    /// Prints payloads of planet events as they are published
+    TailEvents {
+        /// Number of already published events to print first
+        #[arg(long, default_value_t = 0)]
+        last: usize,
+    },
+    /// Creates indexes of MongoDB collections the same way as the service does on startup
+    EnsureIndexes,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    dotenv::from_filename(".env.local").ok();
+    let cli = Cli::parse();
+
+    let config = AppConfig::load().unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1)
+    });
+    let mongodb_client = MongoDbClient::new(&config.mongodb).await;
+
+    let result = run(cli.command, &config, &mongodb_client).await;
+    mongodb_client.shutdown().await;
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+async fn run(
+    command: Command,
+    config: &AppConfig,
+    mongodb_client: &MongoDbClient,
+) -> Result<(), CustomError> {
+    match command {
+        Command::Seed { file } => {
+            // planets are upserted by name, which relies on the unique index
+            mongodb_client.ensure_indexes().await?;
+            let planet_service = create_planet_service(config, mongodb_client).await?;
+            seed(&planet_service, &file).await
+        }
+        Command::List {
+            planet_type,
+            include_deleted,
+        } => {
+            let planet_service = create_planet_service(config, mongodb_client).await?;
+            let planets = planet_service
+                .get_planets(planet_type, include_deleted)
+                .await?;
+            for planet in planets {
+                print_planet(&planet);
+            }
+            Ok(())
+        }
+        Command::Validate => validate(mongodb_client).await,
+        Command::WarmCache => {
+            let planet_service = create_planet_service(config, mongodb_client).await?;
+            let count = planet_service.warm_cache().await?;
+            println!("Cached {} planets and their statistics", count);
+            Ok(())
+        }
+        Command::FlushCache { prefix } => {
+            let planet_service = create_planet_service(config, mongodb_client).await?;
+            let deleted = planet_service.flush_cache(prefix.as_deref()).await?;
+            println!("Deleted {} cache entries", deleted);
+            Ok(())
+        }
+        Command::TailEvents { last } => tail_events(config, last).await,
+        Command::EnsureIndexes => {
+            for index_name in mongodb_client.ensure_indexes().await? {
+                println!("{}", index_name);
+            }
+            Ok(())
+        }
+    }
+}
+
+async fn create_planet_service(
+    config: &AppConfig,
+    mongodb_client: &MongoDbClient,
+) -> Result<PlanetService, CustomError> {
+    let redis_connection_manager = redis::create_client(config.redis.uri.clone())
+        .await?
+        .get_tokio_connection_manager()
+        .await?;
+
+    Ok(PlanetService::new(
+        Arc::new(mongodb_client.clone()),
+        Arc::new(redis_connection_manager.clone()),
+        Arc::new(redis_connection_manager),
+        EventPublishing::from(&config.events),
+    ))
+}
+
+// all planets are checked before any of them is saved
+async fn seed(planet_service: &PlanetService, file: &Path) -> Result<(), CustomError> {
+    let planets = read_planets(file)?;
+    for planet in planets.iter() {
+        let problems = planet.validate();
+        if !problems.is_empty() {
+            return Err(CustomError::BadRequest {
+                message: format!("Planet {} is invalid: {}", planet.name, problems.join("; ")),
+            });
+        }
+    }
+
+    for planet in planets {
+        let (planet, created) = planet_service.upsert_planet_by_name(planet, ACTOR).await?;
+        let action = if created { "Created" } else { "Updated" };
+        println!("{} {}", action, planet.name);
+    }
+    Ok(())
+}
+
+fn read_planets(file: &Path) -> Result<Vec<Planet>, CustomError> {
+    let invalid_file = |message: String| CustomError::BadRequest {
+        message: format!("Can't read planets from {}: {}", file.display(), message),
+    };
+
+    let content = fs::read_to_string(file).map_err(|e| invalid_file(e.to_string()))?;
+    let values: Vec<serde_json::Value> =
+        serde_json::from_str(&content).map_err(|e| invalid_file(e.to_string()))?;
+
+    values
+        .into_iter()
+        .map(|value| {
+            let value = Bson::try_from(value).map_err(|e| invalid_file(e.to_string()))?;
+            bson::from_bson(value).map_err(|e| invalid_file(e.to_string()))
+        })
+        .collect()
+}
+
+fn print_planet(planet: &Planet) {
+    let id = planet.id.map(|id| id.to_string()).unwrap_or_default();
+    let satellites = planet.satellites.as_ref().map_or(0, Vec::len);
+    let deleted = if planet.deleted_at.is_some() {
+        "\tdeleted"
+    } else {
+        ""
+    };
+    println!(
+        "{}\t{}\t{}\t{}\t{} satellites{}",
+        id, planet.name, planet.r#type, planet.mean_radius, satellites, deleted
+    );
+}
+
+// documents are checked as they are stored, so that those that can't be read are reported too
+async fn validate(mongodb_client: &MongoDbClient) -> Result<(), CustomError> {
+    let documents = mongodb_client.get_planet_documents().await?;
+    let total = documents.len();
+
+    let mut problem_count = 0;
+    let mut ids_by_name: HashMap<String, Vec<String>> = HashMap::new();
+    for document in documents {
+        let id = document
+            .get("_id")
+            .map(|id| id.to_string())
+            .unwrap_or_default();
+
+        let problems = match bson::from_document::<Planet>(document) {
+            Ok(planet) => {
+                // deleted planets don't prevent creation of planets with the same name
+                if planet.deleted_at.is_none() {
+                    ids_by_name
+                        .entry(planet.name.clone())
+                        .or_default()
+                        .push(id.clone());
+                }
+                planet.validate()
+            }
+            Err(e) => vec![format!("can't be read: {}", e)],
+        };
+
+        if !problems.is_empty() {
+            problem_count += problems.len();
+            println!("{}: {}", id, problems.join("; "));
+        }
+    }
+
+    for (name, ids) in ids_by_name {
+        if ids.len() > 1 {
+            problem_count += 1;
+            println!("{}: name {} is not unique", ids.join(", "), name);
+        }
+    }
+
+    println!("Checked {} planets", total);
+    if problem_count == 0 {
+        Ok(())
+    } else {
+        Err(CustomError::BadRequest {
+            message: format!("Found {} problems with planets", problem_count),
+        })
+    }
+}
+
+// the subscription is made before reading the stream, so no event is missed in between; events
+// that are received from both are printed once since messages contain IDs of stream entries
+async fn tail_events(config: &AppConfig, last: usize) -> Result<(), CustomError> {
+    let redis_client = redis::create_client(config.redis.uri.clone()).await?;
+    let mut pubsub_con = redis::subscribe(&redis_client).await?;
+
+    let mut position = StreamPosition::default();
+    if last > 0 {
+        for (stream_id, payload) in redis::get_last_planet_events(&redis_client, last).await? {
+            position.advance(&stream_id);
+            println!("{}", payload);
+        }
+    }
+
+    let mut messages = pubsub_con.on_message();
+    while let Some(msg) = messages.next().await {
+        let payload = msg.get_payload::<String>()?;
+        let is_new = match redis::stream_id_of(&payload) {
+            Some(stream_id) => position.advance(&stream_id),
+            None => true,
+        };
+        if is_new {
+            println!("{}", payload);
+        }
+    }
+
+    Err(CustomError::RedisError {
+        message: String::from("Redis Pub/Sub connection is lost"),
+    })
+}
+
+fn parse_planet_type(value: &str) -> Result<PlanetType, String> {
+    serde_json::from_value(serde_json::Value::from(value))
+        .map_err(|_| String::from("expected TerrestrialPlanet, GasGiant, IceGiant or DwarfPlanet"))
+}
@@ -2,20 +2,47 @@ use std::sync::Mutex;
 use std::time::Duration;
 
 use actix_web::web::{Bytes, Data};
+use chrono::{DateTime, Utc};
 use tokio::sync::mpsc::{self, Receiver, Sender};
+use tokio::task::JoinHandle;
 use tokio::time;
+use tracing::{debug, info_span};
 
+use crate::dto::PlanetMessage;
 use crate::errors::CustomError;
+use crate::telemetry;
+
+// how long SSE clients should wait before reconnecting after the server is shut down
+const SHUTDOWN_RETRY: Duration = Duration::from_secs(5);
+const CLIENT_CHANNEL_CAPACITY: usize = 100;
 
-#[derive(Clone)]
 pub struct Broadcaster {
-    clients: Vec<Sender<Result<Bytes, CustomError>>>,
+    clients: Vec<Client>,
+    // receive raw payloads of planet events, e.g. GraphQL subscriptions
+    subscribers: Vec<Sender<String>>,
+    ping_task: Option<JoinHandle<()>>,
+}
+
+#[derive(Clone)]
+struct Client {
+    sender: Sender<Result<Bytes, CustomError>>,
+    address: Option<String>,
+    connected_at: DateTime<Utc>,
+}
+
+pub struct ClientInfo {
+    pub address: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    /// Messages that are sent but not yet delivered to the client
+    pub queued_messages: usize,
 }
 
 impl Broadcaster {
     fn new() -> Self {
         Broadcaster {
             clients: Vec::new(),
+            subscribers: Vec::new(),
+            ping_task: None,
         }
     }
 
@@ -23,33 +50,106 @@ impl Broadcaster {
         let me = Data::new(Mutex::new(Broadcaster::new()));
 
         // ping clients every 10 seconds to see if they are alive
-        Broadcaster::spawn_ping(me.clone());
+        let ping_task = Broadcaster::spawn_ping(me.clone());
+        me.lock().expect("Can't lock broadcaster").ping_task = Some(ping_task);
 
         me
     }
 
-    pub async fn new_client(&mut self) -> Receiver<Result<Bytes, CustomError>> {
-        let (tx, rx) = mpsc::channel::<Result<Bytes, CustomError>>(100);
+    pub fn new_client(&mut self, address: Option<String>) -> Receiver<Result<Bytes, CustomError>> {
+        let (tx, rx) = mpsc::channel::<Result<Bytes, CustomError>>(CLIENT_CHANNEL_CAPACITY);
 
-        tx.clone()
-            .send(Ok(Bytes::from("data: Connected\n\n")))
-            .await
+        tx.try_send(Ok(Bytes::from("data: Connected\n\n")))
             .expect("Can't create a client");
 
-        self.clients.push(tx);
+        self.clients.push(Client {
+            sender: tx,
+            address,
+            connected_at: Utc::now(),
+        });
         crate::metrics::HTTP_CONNECTED_SSE_CLIENTS.inc();
         rx
     }
 
+    pub fn new_subscriber(&mut self) -> Receiver<String> {
+        let (tx, rx) = mpsc::channel::<String>(CLIENT_CHANNEL_CAPACITY);
+        self.subscribers.push(tx);
+        rx
+    }
+
     pub fn send(&self, msg: Bytes) {
+        // clients that are gone or can't keep up are removed by the next ping
         for client in self.clients.iter() {
-            client
-                .try_send(Ok(msg.clone()))
-                .expect("Can't send a message");
+            if let Err(e) = client.sender.try_send(Ok(msg.clone())) {
+                debug!("Can't send a message to a client: {}", e);
+            }
+        }
+    }
+
+    /// Delivery of the event is traced as a part of the request that created the planet
+    pub fn send_planet_created(&self, payload: &str) {
+        let span = info_span!(
+            "deliver planet event",
+            otel.kind = "consumer",
+            sse.clients = self.clients.len(),
+            graphql.subscribers = self.subscribers.len(),
+        );
+        // the trace context and the stream ID aren't sent to clients
+        let payload = match serde_json::from_str::<PlanetMessage>(payload) {
+            Ok(mut message) => {
+                telemetry::continue_trace(&span, &message.trace_context);
+                message.trace_context.clear();
+                message.stream_id = None;
+                serde_json::to_string(&message).unwrap_or_else(|_| payload.to_string())
+            }
+            Err(_) => payload.to_string(),
+        };
+        let _entered = span.enter();
+
+        self.send(Bytes::from(format!(
+            "data: Planet created: {:?}\n\n",
+            payload
+        )));
+
+        for subscriber in self.subscribers.iter() {
+            if let Err(e) = subscriber.try_send(payload.clone()) {
+                debug!("Can't send a message to a subscriber: {}", e);
+            }
+        }
+    }
+
+    /// Clients that are gone are listed until the next ping
+    pub fn clients(&self) -> Vec<ClientInfo> {
+        self.clients
+            .iter()
+            .map(|client| ClientInfo {
+                address: client.address.clone(),
+                connected_at: client.connected_at,
+                queued_messages: CLIENT_CHANNEL_CAPACITY - client.sender.capacity(),
+            })
+            .collect()
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.subscribers.len()
+    }
+
+    /// Stops pinging and sends the last event to clients; their streams end after it is delivered
+    pub fn shutdown(&mut self) {
+        if let Some(ping_task) = self.ping_task.take() {
+            ping_task.abort();
         }
+
+        self.send(Bytes::from(format!(
+            "event: shutdown\nretry: {}\ndata: Server is shutting down\n\n",
+            SHUTDOWN_RETRY.as_millis()
+        )));
+        self.clients.clear();
+        self.subscribers.clear();
+        crate::metrics::HTTP_CONNECTED_SSE_CLIENTS.set(0);
     }
 
-    fn spawn_ping(me: Data<Mutex<Self>>) {
+    fn spawn_ping(me: Data<Mutex<Self>>) -> JoinHandle<()> {
         tokio::spawn(async move {
             let mut interval = time::interval(Duration::from_secs(10));
 
@@ -60,18 +160,20 @@ impl Broadcaster {
                 crate::metrics::HTTP_CONNECTED_SSE_CLIENTS
                     .set(broadcaster_mutex.clients.len() as i64)
             }
-        });
+        })
     }
 
     fn remove_stale_clients(&mut self) {
         let mut ok_clients = Vec::new();
         for client in self.clients.iter() {
-            let result = client.clone().try_send(Ok(Bytes::from("data: Ping\n\n")));
+            let result = client.sender.try_send(Ok(Bytes::from("data: Ping\n\n")));
 
             if let Ok(()) = result {
                 ok_clients.push(client.clone());
             }
         }
         self.clients = ok_clients;
+        self.subscribers
+            .retain(|subscriber| !subscriber.is_closed());
     }
 }
@@ -0,0 +1,158 @@
+use std::cmp;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use mongodb::bson::Document;
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType};
+use mongodb::error::ErrorKind;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tokio_stream::StreamExt;
+use tracing::{debug, error, info, warn};
+
+use crate::broadcaster::Broadcaster;
+use crate::db::MongoDbClient;
+use crate::dto::PlanetMessage;
+use crate::errors::CustomError;
+use crate::model::Planet;
+use crate::services::PlanetService;
+
+const WATCH_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const WATCH_MAX_BACKOFF: Duration = Duration::from_secs(30);
+// the resume point is no longer in the oplog
+const CHANGE_STREAM_HISTORY_LOST_CODE: i32 = 286;
+
+/// Watches `planets` collection and uses its changes as the source of events instead of
+/// the application code, so that modifications made directly in MongoDB are also taken into account.
+/// Change streams are only available on replica sets and sharded clusters. Every instance of the
+/// application should have its own consumer name since it notifies its own SSE clients
+pub fn start_change_stream(
+    mongodb_client: MongoDbClient,
+    planet_service: Data<PlanetService>,
+    broadcaster: Data<Mutex<Broadcaster>>,
+    consumer: String,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut backoff = WATCH_INITIAL_BACKOFF;
+
+        loop {
+            match watch_planets(&mongodb_client, &planet_service, &broadcaster, &consumer).await {
+                Ok(()) => {
+                    warn!("MongoDB change stream is closed, restarting");
+                    backoff = WATCH_INITIAL_BACKOFF;
+                }
+                Err(e) => {
+                    error!(
+                        "MongoDB change stream failed, next attempt in {:?}: {}",
+                        backoff, e
+                    );
+                    time::sleep(backoff).await;
+                    backoff = cmp::min(backoff * 2, WATCH_MAX_BACKOFF);
+                }
+            }
+        }
+    })
+}
+
+async fn watch_planets(
+    mongodb_client: &MongoDbClient,
+    planet_service: &PlanetService,
+    broadcaster: &Data<Mutex<Broadcaster>>,
+    consumer: &str,
+) -> Result<(), CustomError> {
+    let resume_token = mongodb_client.get_resume_token(consumer).await?;
+    let is_resumed = resume_token.is_some();
+
+    let mut change_stream = match mongodb_client.watch_planets(resume_token).await {
+        Err(e) if is_history_lost(&e) => return resume_from_now(mongodb_client, consumer).await,
+        result => result?,
+    };
+    info!(
+        "MongoDB change stream is started for {} (resumed: {})",
+        consumer, is_resumed
+    );
+
+    while let Some(event) = change_stream.next().await {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) if is_history_lost(&e) => {
+                return resume_from_now(mongodb_client, consumer).await;
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        // if handling fails, the token isn't saved and the stream is restarted from the event
+        handle_event(&event, planet_service, broadcaster).await?;
+
+        mongodb_client.save_resume_token(consumer, event.id).await?;
+    }
+
+    Ok(())
+}
+
+/// Documents are decoded per event, so that one written directly to MongoDB that can't be read
+/// as a planet is logged and skipped instead of blocking the stream
+pub async fn handle_event(
+    event: &ChangeStreamEvent<Document>,
+    planet_service: &PlanetService,
+    broadcaster: &Data<Mutex<Broadcaster>>,
+) -> Result<(), CustomError> {
+    let planet_id = event
+        .document_key
+        .as_ref()
+        .and_then(|document_key| document_key.get_object_id("_id").ok())
+        .map(|id| id.to_string());
+
+    match (&event.operation_type, planet_id) {
+        (OperationType::Insert, planet_id) => {
+            let document = event.full_document.clone().ok_or(CustomError::NotFound {
+                message: String::from("Change event doesn't contain a created planet"),
+            })?;
+            match mongodb::bson::from_document::<Planet>(document) {
+                Ok(planet) => {
+                    let payload = serde_json::to_string(&PlanetMessage::from(&planet))?;
+                    broadcaster
+                        .lock()
+                        .expect("Can't lock broadcaster")
+                        .send_planet_created(&payload);
+                }
+                Err(e) => {
+                    error!(
+                        "Skip a created planet that can't be read: {:?}: {}",
+                        planet_id, e
+                    );
+                    if let Some(planet_id) = planet_id {
+                        planet_service.invalidate_planet_cache(&planet_id).await?;
+                    }
+                }
+            }
+            planet_service.invalidate_stats_cache().await?;
+        }
+        (
+            OperationType::Update | OperationType::Replace | OperationType::Delete,
+            Some(planet_id),
+        ) => {
+            debug!("Invalidate cache of a planet by id: {}", planet_id);
+            planet_service.invalidate_planet_cache(&planet_id).await?;
+        }
+        (operation_type, _) => debug!("Skip MongoDB change event: {:?}", operation_type),
+    }
+
+    Ok(())
+}
+
+fn is_history_lost(error: &mongodb::error::Error) -> bool {
+    matches!(
+        *error.kind,
+        ErrorKind::Command(ref command_error) if command_error.code == CHANGE_STREAM_HISTORY_LOST_CODE
+    )
+}
+
+async fn resume_from_now(
+    mongodb_client: &MongoDbClient,
+    consumer: &str,
+) -> Result<(), CustomError> {
+    warn!("Saved resume token is not valid anymore, events happened since then are lost");
+    mongodb_client.delete_resume_token(consumer).await
+}
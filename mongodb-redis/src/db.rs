@@ -1,38 +1,490 @@
-use mongodb::bson::{doc, oid::ObjectId, Document};
-use mongodb::{Client, Collection};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use mongodb::change_stream::event::{ChangeStreamEvent, ResumeToken};
+use mongodb::change_stream::ChangeStream;
+use mongodb::error::{
+    ErrorKind, WriteFailure, TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT,
+};
+use mongodb::event::command::{CommandEventHandler, CommandFailedEvent, CommandSucceededEvent};
+use mongodb::options::{
+    ChangeStreamOptions, ClientOptions, FindOneAndUpdateOptions, FindOneOptions, FindOptions,
+    FullDocumentType, IndexOptions, ReplaceOptions, UpdateOptions,
+};
+use mongodb::{Client, ClientSession, Collection, Cursor, IndexModel};
 use rust_embed::RustEmbed;
+use serde::{Deserialize, Serialize};
 use tokio_stream::StreamExt;
+use tracing::instrument;
 
+use crate::config::{CollectionsConfig, MongoDbConfig};
 use crate::errors::CustomError;
 use crate::errors::CustomError::NotFound;
-use crate::model::{Planet, PlanetType};
+use crate::model::{OutboxEvent, Planet, PlanetStats, PlanetType, Revision, RevisionOperation};
+use crate::storage::{PlanetRepository, PlanetStream};
 
-const DB_NAME: &str = "solar_system_info";
-const COLLECTION_NAME: &str = "planets";
+// how many times a transaction, its commit or an insert of a revision is tried before an error is
+// returned
+const MAX_WRITE_ATTEMPTS: usize = 5;
+const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+const UNIQUE_NAME_INDEX_NAME: &str = "name_unique_if_not_deleted";
 
+/// Each call is traced in a span named after the method
 #[derive(Clone, Debug)]
 pub struct MongoDbClient {
     client: Client,
+    database: String,
+    collections: CollectionsConfig,
+    // transactions are supported only by replica sets and sharded clusters
+    supports_transactions: bool,
 }
 
 impl MongoDbClient {
-    pub async fn new(mongodb_uri: String) -> Self {
-        let mongodb_client = Client::with_uri_str(mongodb_uri)
+    pub async fn new(config: &MongoDbConfig) -> Self {
+        let mut options = ClientOptions::parse(&config.uri)
+            .await
+            .expect("Failed to parse MongoDB URI");
+        options.command_event_handler = Some(Arc::new(CommandMetrics));
+        let mongodb_client =
+            Client::with_options(options).expect("Failed to create MongoDB client");
+
+        let hello = mongodb_client
+            .database("admin")
+            .run_command(doc! { "hello": 1 }, None)
             .await
-            .expect("Failed to create MongoDB client");
+            .expect("Failed to connect to MongoDB");
+        let supports_transactions =
+            hello.contains_key("setName") || hello.get_str("msg").ok() == Some("isdbgrid");
 
         MongoDbClient {
             client: mongodb_client,
+            database: config.database.clone(),
+            collections: config.collections.clone(),
+            supports_transactions,
+        }
+    }
+
+    /// Closes connections once cursors and sessions are dropped
+    pub async fn shutdown(self) {
+        self.client.shutdown().await;
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn get_planets_cursor(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+    ) -> Result<Cursor<Planet>, CustomError> {
+        Ok(self
+            .get_planets_collection()
+            .find(planets_filter(planet_type, include_deleted), None)
+            .await?)
+    }
+
+    /// Creates indexes used by queries of the application, including the unique ones that keep
+    /// names of planets and revision numbers of a planet unique; existing indexes are left as is.
+    /// Called on startup. Fails if existing planets have duplicate names. Returns names of
+    /// the indexes
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    pub async fn ensure_indexes(&self) -> Result<Vec<String>, CustomError> {
+        let planet_indexes = vec![
+            // names of planets that aren't deleted are unique; partial indexes don't support
+            // `$exists: false`, so `deleted_at` is a part of the key instead: it is missing in all
+            // planets that aren't deleted and differs between deleted ones
+            IndexModel::builder()
+                .keys(doc! { "name": 1, "deleted_at": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .name(String::from(UNIQUE_NAME_INDEX_NAME))
+                        .unique(true)
+                        .build(),
+                )
+                .build(),
+            IndexModel::builder().keys(doc! { "type": 1 }).build(),
+            IndexModel::builder().keys(doc! { "deleted_at": 1 }).build(),
+        ];
+        let revision_indexes = vec![IndexModel::builder()
+            .keys(doc! { "planet_id": 1, "revision": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build()];
+
+        let mut result = self
+            .get_planets_collection()
+            .create_indexes(planet_indexes, None)
+            .await
+            .map_err(|e| {
+                if is_duplicate_key_error(&e) {
+                    CustomError::Conflict {
+                        message: format!(
+                            "Can't create unique index {} since names of some planets that aren't deleted are duplicated; run `mongodb-redis-cli validate` to find them: {}",
+                            UNIQUE_NAME_INDEX_NAME, e
+                        ),
+                    }
+                } else {
+                    CustomError::from(e)
+                }
+            })?
+            .index_names;
+        result.extend(
+            self.get_revisions_collection()
+                .create_indexes(revision_indexes, None)
+                .await?
+                .index_names,
+        );
+
+        Ok(result)
+    }
+
+    /// Returns planets including deleted ones as raw documents, so that those which can't be
+    /// deserialized can be found
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    pub async fn get_planet_documents(&self) -> Result<Vec<Document>, CustomError> {
+        let mut documents = self
+            .client
+            .database(&self.database)
+            .collection::<Document>(&self.collections.planets)
+            .find(None, None)
+            .await?;
+
+        let mut result: Vec<Document> = Vec::new();
+        while let Some(document) = documents.next().await {
+            result.push(document?);
+        }
+
+        Ok(result)
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    pub async fn get_outbox_events(&self, limit: i64) -> Result<Vec<OutboxEvent>, CustomError> {
+        let options = FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .limit(limit)
+            .build();
+        let mut events = self.get_outbox_collection().find(None, options).await?;
+
+        let mut result: Vec<OutboxEvent> = Vec::new();
+        while let Some(event) = events.next().await {
+            result.push(event?);
+        }
+
+        Ok(result)
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb", event.id = %id))]
+    pub async fn delete_outbox_event(&self, id: ObjectId) -> Result<(), CustomError> {
+        let filter = doc! { "_id": &id };
+        self.get_outbox_collection()
+            .delete_one(filter, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Permanently removes planets deleted before the specified time
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    pub async fn purge_deleted_planets(
+        &self,
+        deleted_before: mongodb::bson::DateTime,
+    ) -> Result<u64, CustomError> {
+        let filter = doc! { "deleted_at": { "$lt": deleted_before } };
+        let delete_result = self
+            .get_planets_collection()
+            .delete_many(filter, None)
+            .await?;
+
+        Ok(delete_result.deleted_count)
+    }
+
+    /// Transactions are supported only by replica sets and sharded clusters
+    pub fn supports_transactions(&self) -> bool {
+        self.supports_transactions
+    }
+
+    /// Runs the operation in a transaction; the whole transaction is retried on transient errors
+    /// and the commit is retried if its result is unknown. The session is passed to the operation
+    /// and returned back, so that it can be used across attempts. Fails if transactions aren't
+    /// supported, so that writes which must be atomic, e.g. of a planet and its outbox event,
+    /// are never made partially
+    async fn with_transaction<T, F, Fut>(&self, operation: F) -> Result<T, CustomError>
+    where
+        F: Fn(ClientSession) -> Fut,
+        Fut: Future<Output = (ClientSession, mongodb::error::Result<T>)>,
+    {
+        if !self.supports_transactions {
+            return Err(CustomError::MongoDbError {
+                message: String::from("Transactions require a replica set or a sharded cluster"),
+            });
+        }
+
+        let mut session = self.client.start_session(None).await?;
+        let mut attempt = 1;
+        loop {
+            session.start_transaction(None).await?;
+            let (returned_session, result) = operation(session).await;
+            session = returned_session;
+
+            let result = match result {
+                Ok(value) => commit_transaction(&mut session).await.map(|_| value),
+                Err(e) => {
+                    // the transaction may be already aborted by the server
+                    session.abort_transaction().await.ok();
+                    Err(e)
+                }
+            };
+            match result {
+                Err(e)
+                    if e.contains_label(TRANSIENT_TRANSACTION_ERROR)
+                        && attempt < MAX_WRITE_ATTEMPTS =>
+                {
+                    attempt += 1;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    /// A change of a planet and its revision are saved in a transaction if it's supported;
+    /// otherwise the operation is run once in a session without a transaction, so a revision can
+    /// be lost if its insert fails
+    async fn with_transaction_if_supported<T, F, Fut>(&self, operation: F) -> Result<T, CustomError>
+    where
+        F: Fn(ClientSession) -> Fut,
+        Fut: Future<Output = (ClientSession, mongodb::error::Result<T>)>,
+    {
+        if self.supports_transactions {
+            return self.with_transaction(operation).await;
+        }
+
+        let session = self.client.start_session(None).await?;
+        Ok(operation(session).await.1?)
+    }
+
+    /// Numbers are allocated as the last one + 1 and kept unique by an index. In a transaction,
+    /// concurrent changes of the same planet conflict and the transaction is retried; otherwise
+    /// the insert is retried if the number is taken
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn record_revision(
+        &self,
+        operation: RevisionOperation,
+        actor: &str,
+        before: Option<&Planet>,
+        after: Option<&Planet>,
+        session: &mut ClientSession,
+    ) -> mongodb::error::Result<()> {
+        let planet_id = before
+            .or(after)
+            .and_then(|planet| planet.id)
+            .expect("Planet.id is not specified");
+        let before = before.map(Document::from);
+        let after = after.map(Document::from);
+
+        let collection = self.get_revisions_collection();
+        let filter = doc! { "planet_id": &planet_id };
+        let options = FindOneOptions::builder()
+            .sort(doc! { "revision": -1 })
+            .build();
+        let mut attempt = 1;
+        loop {
+            let last_revision = collection
+                .find_one_with_session(filter.clone(), options.clone(), session)
+                .await?;
+
+            let revision = Revision {
+                id: None,
+                planet_id,
+                revision: last_revision.map_or(1, |last_revision| last_revision.revision + 1),
+                operation,
+                actor: actor.to_string(),
+                timestamp: mongodb::bson::DateTime::now(),
+                diff: diff(before.as_ref(), after.as_ref()),
+                before: before.clone(),
+                after: after.clone(),
+            };
+
+            match collection
+                .insert_one_with_session(revision, None, session)
+                .await
+            {
+                Err(e)
+                    if is_duplicate_key_error(&e)
+                        && !self.supports_transactions
+                        && attempt < MAX_WRITE_ATTEMPTS =>
+                {
+                    attempt += 1;
+                }
+                result => return result.map(|_| ()),
+            }
+        }
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    pub async fn watch_planets(
+        &self,
+        resume_token: Option<ResumeToken>,
+    ) -> mongodb::error::Result<ChangeStream<ChangeStreamEvent<Document>>> {
+        let options = ChangeStreamOptions::builder()
+            .full_document(Some(FullDocumentType::UpdateLookup))
+            .resume_after(resume_token)
+            .build();
+
+        // documents aren't deserialized as planets here, so that an invalid one doesn't fail
+        // the stream
+        self.get_planets_collection()
+            .clone_with_type::<Document>()
+            .watch(None, options)
+            .await
+    }
+
+    /// Each consumer of the change stream keeps its own position in it
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    pub async fn get_resume_token(
+        &self,
+        consumer: &str,
+    ) -> Result<Option<ResumeToken>, CustomError> {
+        let filter = doc! { "_id": self.get_resume_token_id(consumer) };
+        let saved_token = self
+            .get_resume_tokens_collection()
+            .find_one(filter, None)
+            .await?;
+
+        Ok(saved_token.map(|saved_token| saved_token.token))
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    pub async fn save_resume_token(
+        &self,
+        consumer: &str,
+        token: ResumeToken,
+    ) -> Result<(), CustomError> {
+        let query = doc! { "_id": self.get_resume_token_id(consumer) };
+        let update = doc! { "$set": { "token": mongodb::bson::to_bson(&token)? } };
+        let options = UpdateOptions::builder().upsert(true).build();
+        self.get_resume_tokens_collection()
+            .update_one(query, update, options)
+            .await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    pub async fn delete_resume_token(&self, consumer: &str) -> Result<(), CustomError> {
+        let filter = doc! { "_id": self.get_resume_token_id(consumer) };
+        self.get_resume_tokens_collection()
+            .delete_one(filter, None)
+            .await?;
+
+        Ok(())
+    }
+
+    fn get_resume_token_id(&self, consumer: &str) -> String {
+        format!("{}:{}", self.collections.planets, consumer)
+    }
+
+    fn get_planets_collection(&self) -> Collection<Planet> {
+        self.client
+            .database(&self.database)
+            .collection::<Planet>(&self.collections.planets)
+    }
+
+    fn get_revisions_collection(&self) -> Collection<Revision> {
+        self.client
+            .database(&self.database)
+            .collection::<Revision>(&self.collections.planet_revisions)
+    }
+
+    fn get_outbox_collection(&self) -> Collection<OutboxEvent> {
+        self.client
+            .database(&self.database)
+            .collection::<OutboxEvent>(&self.collections.outbox)
+    }
+
+    fn get_resume_tokens_collection(&self) -> Collection<SavedResumeToken> {
+        self.client
+            .database(&self.database)
+            .collection::<SavedResumeToken>(&self.collections.resume_tokens)
+    }
+}
+
+#[async_trait]
+impl PlanetRepository for MongoDbClient {
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn ping(&self) -> Result<(), CustomError> {
+        self.client
+            .database(&self.database)
+            .run_command(doc! { "ping": 1 }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn get_planets(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+    ) -> Result<Vec<Planet>, CustomError> {
+        let mut planets = self
+            .get_planets_cursor(planet_type, include_deleted)
+            .await?;
+
+        let mut result: Vec<Planet> = Vec::new();
+        while let Some(planet) = planets.next().await {
+            result.push(planet?);
         }
+
+        Ok(result)
     }
 
-    pub async fn get_planets(
+    /// Unlike `get_planets` the result isn't collected, so it can be streamed
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn get_planets_stream(
         &self,
         planet_type: Option<PlanetType>,
+        include_deleted: bool,
+    ) -> Result<PlanetStream, CustomError> {
+        let planets = self
+            .get_planets_cursor(planet_type, include_deleted)
+            .await?;
+
+        Ok(Box::pin(
+            planets.map(|planet| planet.map_err(CustomError::from)),
+        ))
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn get_planets_page(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+        skip: u64,
+        limit: i64,
     ) -> Result<Vec<Planet>, CustomError> {
-        let filter = planet_type.map(|pt| {
-            doc! { "type": pt.to_string() }
-        });
+        let find_options = FindOptions::builder()
+            .sort(doc! { "_id": 1 })
+            .skip(skip)
+            .limit(limit)
+            .build();
+
+        let mut planets = self
+            .get_planets_collection()
+            .find(planets_filter(planet_type, include_deleted), find_options)
+            .await?;
+
+        let mut result: Vec<Planet> = Vec::new();
+        while let Some(planet) = planets.next().await {
+            result.push(planet?);
+        }
+
+        Ok(result)
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn get_planets_by_ids(&self, ids: Vec<ObjectId>) -> Result<Vec<Planet>, CustomError> {
+        let mut filter = doc! { "_id": { "$in": ids } };
+        filter.extend(not_deleted());
 
         let mut planets = self.get_planets_collection().find(filter, None).await?;
 
@@ -44,59 +496,543 @@ impl MongoDbClient {
         Ok(result)
     }
 
-    pub async fn create_planet(&self, planet: Planet) -> Result<Planet, CustomError> {
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn get_planet_stats(&self) -> Result<PlanetStats, CustomError> {
+        let pipeline = vec![
+            doc! { "$match": not_deleted() },
+            doc! { "$facet": {
+                "count_by_type": [
+                    { "$group": { "_id": "$type", "count": { "$sum": 1 } } },
+                    { "$sort": { "_id": 1 } },
+                ],
+                "measurements": [
+                    { "$group": {
+                        "_id": null,
+                        "min_mean_radius": { "$min": "$mean_radius" },
+                        "max_mean_radius": { "$max": "$mean_radius" },
+                        "avg_mean_radius": { "$avg": "$mean_radius" },
+                        "total_satellites": { "$sum": { "$size": { "$ifNull": ["$satellites", []] } } },
+                    } },
+                ],
+                "landings": [
+                    { "$unwind": "$satellites" },
+                    { "$match": { "satellites.first_spacecraft_landing_date": { "$ne": null } } },
+                    { "$group": {
+                        "_id": null,
+                        "earliest_landing_date": { "$min": "$satellites.first_spacecraft_landing_date" },
+                        "latest_landing_date": { "$max": "$satellites.first_spacecraft_landing_date" },
+                    } },
+                ],
+            } },
+            // facets with empty input produce empty arrays
+            doc! { "$project": {
+                "count_by_type": 1,
+                "min_mean_radius": { "$first": "$measurements.min_mean_radius" },
+                "max_mean_radius": { "$first": "$measurements.max_mean_radius" },
+                "avg_mean_radius": { "$first": "$measurements.avg_mean_radius" },
+                "total_satellites": { "$ifNull": [{ "$first": "$measurements.total_satellites" }, 0] },
+                "earliest_landing_date": { "$first": "$landings.earliest_landing_date" },
+                "latest_landing_date": { "$first": "$landings.latest_landing_date" },
+            } },
+        ];
+
+        let mut result = self
+            .get_planets_collection()
+            .aggregate(pipeline, None)
+            .await?;
+        let stats = result.next().await.ok_or(NotFound {
+            message: String::from("Can't calculate planet statistics"),
+        })??;
+
+        Ok(mongodb::bson::from_document(stats)?)
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn find_planet_by_name(&self, name: &str) -> Result<Option<Planet>, CustomError> {
+        let mut filter = doc! { "name": name };
+        filter.extend(not_deleted());
+
+        Ok(self.get_planets_collection().find_one(filter, None).await?)
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn create_planet(&self, planet: Planet, actor: &str) -> Result<Planet, CustomError> {
+        let planet = &planet;
+        let created_planet = self
+            .with_transaction_if_supported(|mut session| async move {
+                let result = async {
+                    let collection = self.get_planets_collection();
+                    let insert_result = collection
+                        .insert_one_with_session(planet, None, &mut session)
+                        .await?;
+                    let filter = doc! { "_id": &insert_result.inserted_id };
+                    let created_planet = collection
+                        .find_one_with_session(filter, None, &mut session)
+                        .await?;
+                    if let Some(created_planet) = &created_planet {
+                        self.record_revision(
+                            RevisionOperation::Create,
+                            actor,
+                            None,
+                            Some(created_planet),
+                            &mut session,
+                        )
+                        .await?;
+                    }
+                    Ok(created_planet)
+                }
+                .await;
+                (session, result)
+            })
+            .await?;
+
+        created_planet.ok_or(NotFound {
+            message: String::from("Can't find a created planet"),
+        })
+    }
+
+    /// Saves a planet and an event about it atomically; requires a replica set
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn create_planet_with_event(
+        &self,
+        planet: Planet,
+        event: OutboxEvent,
+        actor: &str,
+    ) -> Result<Planet, CustomError> {
         let collection = self.get_planets_collection();
 
-        let insert_result = collection.insert_one(planet, None).await?;
-        let filter = doc! { "_id": &insert_result.inserted_id };
+        let (planet, event) = (&planet, &event);
+        self.with_transaction(|mut session| async move {
+            let result = async {
+                self.get_planets_collection()
+                    .insert_one_with_session(planet, None, &mut session)
+                    .await?;
+                self.get_outbox_collection()
+                    .insert_one_with_session(event, None, &mut session)
+                    .await?;
+                self.record_revision(
+                    RevisionOperation::Create,
+                    actor,
+                    None,
+                    Some(planet),
+                    &mut session,
+                )
+                .await
+            }
+            .await;
+            (session, result)
+        })
+        .await?;
+
+        let filter = doc! { "_id": &planet.id };
         collection.find_one(filter, None).await?.ok_or(NotFound {
             message: String::from("Can't find a created planet"),
         })
     }
 
-    pub async fn get_planet(&self, id: ObjectId) -> Result<Planet, CustomError> {
+    #[instrument(skip_all, fields(db.system = "mongodb", planet.id = %id))]
+    async fn get_planet(&self, id: ObjectId, include_deleted: bool) -> Result<Planet, CustomError> {
         let collection = self.get_planets_collection();
 
-        let filter = doc! { "_id": &id };
+        let mut filter = doc! { "_id": &id };
+        if !include_deleted {
+            filter.extend(not_deleted());
+        }
         collection.find_one(filter, None).await?.ok_or(NotFound {
             message: format!("Can't find a planet by id: {}", &id),
         })
     }
 
-    pub async fn update_planet(&self, id: ObjectId, planet: Planet) -> Result<Planet, CustomError> {
-        let collection = self.get_planets_collection();
+    #[instrument(skip_all, fields(db.system = "mongodb", planet.id = %id))]
+    async fn update_planet(
+        &self,
+        id: ObjectId,
+        planet: Planet,
+        actor: &str,
+    ) -> Result<Planet, CustomError> {
+        let planet = &planet;
+        let updated_planet = self
+            .with_transaction_if_supported(|mut session| async move {
+                let result = async {
+                    let mut query = doc! { "_id": &id };
+                    query.extend(not_deleted());
+                    let update = doc! { "$set": Document::from(planet) };
+                    let collection = self.get_planets_collection();
+                    let planet_before = match collection
+                        .find_one_and_update_with_session(query, update, None, &mut session)
+                        .await?
+                    {
+                        Some(planet_before) => planet_before,
+                        None => return Ok(None),
+                    };
 
-        let query = doc! { "_id": &id };
-        let update = doc! { "$set": Document::from(&planet) };
-        let _update_result = collection.update_one(query, update, None).await?;
+                    let filter = doc! { "_id": &id };
+                    let updated_planet = collection
+                        .find_one_with_session(filter, None, &mut session)
+                        .await?;
+                    if let Some(updated_planet) = &updated_planet {
+                        self.record_revision(
+                            RevisionOperation::Update,
+                            actor,
+                            Some(&planet_before),
+                            Some(updated_planet),
+                            &mut session,
+                        )
+                        .await?;
+                    }
+                    Ok(updated_planet)
+                }
+                .await;
+                (session, result)
+            })
+            .await?;
 
-        let filter = doc! { "_id": &id };
-        collection.find_one(filter, None).await?.ok_or(NotFound {
-            message: format!("Can't find an updated planet by id: {}", &id),
+        updated_planet.ok_or(NotFound {
+            message: format!("Can't find a planet to update by id: {}", &id),
         })
     }
 
-    pub async fn delete_planet(&self, id: ObjectId) -> Result<(), CustomError> {
-        let collection = self.get_planets_collection();
+    /// Planets are matched by the unique index of names, so concurrent calls can't create two
+    /// planets with the same name; if an insert loses the race, the call is retried and updates
+    /// the planet created by another one
+    #[instrument(skip_all, fields(db.system = "mongodb"))]
+    async fn upsert_planet_by_name(
+        &self,
+        planet: Planet,
+        event: Option<OutboxEvent>,
+        actor: &str,
+    ) -> Result<(Planet, bool), CustomError> {
+        // the id of a created planet is chosen in advance, so that the event can refer to it
+        let new_id = planet.id.unwrap_or_default();
+        let mut fields = Document::from(&planet);
+        fields.remove("_id");
+        let (planet, fields, event) = (&planet, &fields, &event);
 
-        let filter = doc! { "_id": &id };
-        collection
-            .find_one_and_delete(filter, None)
+        let mut attempt = 1;
+        let result = loop {
+            let upsert = |mut session: ClientSession| async move {
+                let result = async {
+                    let mut query = doc! { "name": &planet.name };
+                    query.extend(not_deleted());
+                    let update = doc! { "$set": fields, "$setOnInsert": { "_id": new_id } };
+                    let options = FindOneAndUpdateOptions::builder().upsert(true).build();
+                    let collection = self.get_planets_collection();
+                    let planet_before = collection
+                        .find_one_and_update_with_session(query, update, options, &mut session)
+                        .await?;
+
+                    let id = planet_before
+                        .as_ref()
+                        .and_then(|planet_before| planet_before.id)
+                        .unwrap_or(new_id);
+                    let filter = doc! { "_id": &id };
+                    let planet_after = match collection
+                        .find_one_with_session(filter, None, &mut session)
+                        .await?
+                    {
+                        Some(planet_after) => planet_after,
+                        None => return Ok(None),
+                    };
+
+                    let operation = match planet_before {
+                        Some(_) => RevisionOperation::Update,
+                        None => {
+                            if let Some(event) = event {
+                                self.get_outbox_collection()
+                                    .insert_one_with_session(event, None, &mut session)
+                                    .await?;
+                            }
+                            RevisionOperation::Create
+                        }
+                    };
+                    self.record_revision(
+                        operation,
+                        actor,
+                        planet_before.as_ref(),
+                        Some(&planet_after),
+                        &mut session,
+                    )
+                    .await?;
+                    Ok(Some((planet_after, planet_before.is_none())))
+                }
+                .await;
+                (session, result)
+            };
+            // a planet and its outbox event must be saved atomically
+            let result = if event.is_some() {
+                self.with_transaction(&upsert).await
+            } else {
+                self.with_transaction_if_supported(&upsert).await
+            };
+
+            match result {
+                // duplicate keys are reported as conflicts
+                Err(CustomError::Conflict { .. }) if attempt < MAX_WRITE_ATTEMPTS => {
+                    attempt += 1;
+                }
+                result => break result,
+            }
+        };
+
+        result?.ok_or(NotFound {
+            message: format!("Can't find an upserted planet by name: {}", planet.name),
+        })
+    }
+
+    /// Marks a planet as deleted; it is removed from the database later by the purge
+    #[instrument(skip_all, fields(db.system = "mongodb", planet.id = %id))]
+    async fn delete_planet(&self, id: ObjectId, actor: &str) -> Result<(), CustomError> {
+        let deleted_planet = self
+            .with_transaction_if_supported(|mut session| async move {
+                let result = async {
+                    let mut query = doc! { "_id": &id };
+                    query.extend(not_deleted());
+                    let update = doc! { "$set": { "deleted_at": mongodb::bson::DateTime::now() } };
+                    let deleted_planet = self
+                        .get_planets_collection()
+                        .find_one_and_update_with_session(query, update, None, &mut session)
+                        .await?;
+                    if let Some(deleted_planet) = &deleted_planet {
+                        self.record_revision(
+                            RevisionOperation::Delete,
+                            actor,
+                            Some(deleted_planet),
+                            None,
+                            &mut session,
+                        )
+                        .await?;
+                    }
+                    Ok(deleted_planet)
+                }
+                .await;
+                (session, result)
+            })
+            .await?;
+
+        deleted_planet.map(|_| ()).ok_or(NotFound {
+            message: format!("Can't delete a planet by id: {}", id),
+        })
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb", planet.id = %id))]
+    async fn restore_planet(&self, id: ObjectId, actor: &str) -> Result<Planet, CustomError> {
+        let restored_planet = self
+            .with_transaction_if_supported(|mut session| async move {
+                let result = async {
+                    let query = doc! { "_id": &id, "deleted_at": { "$ne": null } };
+                    let update = doc! { "$unset": { "deleted_at": "" } };
+                    let collection = self.get_planets_collection();
+                    let deleted_planet = match collection
+                        .find_one_and_update_with_session(query, update, None, &mut session)
+                        .await?
+                    {
+                        Some(deleted_planet) => deleted_planet,
+                        None => return Ok(None),
+                    };
+
+                    let filter = doc! { "_id": &id };
+                    let restored_planet = collection
+                        .find_one_with_session(filter, None, &mut session)
+                        .await?;
+                    if let Some(restored_planet) = &restored_planet {
+                        self.record_revision(
+                            RevisionOperation::Restore,
+                            actor,
+                            Some(&deleted_planet),
+                            Some(restored_planet),
+                            &mut session,
+                        )
+                        .await?;
+                    }
+                    Ok(restored_planet)
+                }
+                .await;
+                (session, result)
+            })
+            .await?;
+
+        restored_planet.ok_or(NotFound {
+            message: format!("Can't find a deleted planet by id: {}", id),
+        })
+    }
+
+    #[instrument(skip_all, fields(db.system = "mongodb", planet.id = %planet_id))]
+    async fn get_revisions(&self, planet_id: ObjectId) -> Result<Vec<Revision>, CustomError> {
+        let filter = doc! { "planet_id": &planet_id };
+        let options = FindOptions::builder().sort(doc! { "revision": 1 }).build();
+        let mut revisions = self
+            .get_revisions_collection()
+            .find(filter, options)
+            .await?;
+
+        let mut result: Vec<Revision> = Vec::new();
+        while let Some(revision) = revisions.next().await {
+            result.push(revision?);
+        }
+
+        Ok(result)
+    }
+
+    /// Restores the state of a planet right after the specified revision; a planet is recreated if it was deleted
+    #[instrument(skip_all, fields(db.system = "mongodb", planet.id = %id))]
+    async fn revert_planet(
+        &self,
+        id: ObjectId,
+        revision: i64,
+        actor: &str,
+    ) -> Result<Planet, CustomError> {
+        let filter = doc! { "planet_id": &id, "revision": revision };
+        let target_revision = self
+            .get_revisions_collection()
+            .find_one(filter, None)
             .await?
             .ok_or(NotFound {
-                message: format!("Can't delete a planet by id: {}", id),
+                message: format!(
+                    "Can't find revision {} of a planet by id: {}",
+                    revision, &id
+                ),
             })?;
+        let target_state: Planet =
+            mongodb::bson::from_document(target_revision.after.ok_or(NotFound {
+                message: format!(
+                    "Revision {} of a planet by id: {} has no state",
+                    revision, &id
+                ),
+            })?)?;
 
-        Ok(())
+        let target_state = &target_state;
+        let reverted_planet = self
+            .with_transaction_if_supported(|mut session| async move {
+                let result = async {
+                    let collection = self.get_planets_collection();
+                    let filter = doc! { "_id": &id };
+                    let planet_before = collection
+                        .find_one_with_session(filter.clone(), None, &mut session)
+                        .await?;
+                    let options = ReplaceOptions::builder().upsert(true).build();
+                    collection
+                        .replace_one_with_session(
+                            filter.clone(),
+                            target_state,
+                            options,
+                            &mut session,
+                        )
+                        .await?;
+
+                    let reverted_planet = collection
+                        .find_one_with_session(filter, None, &mut session)
+                        .await?;
+                    if let Some(reverted_planet) = &reverted_planet {
+                        self.record_revision(
+                            RevisionOperation::Revert,
+                            actor,
+                            planet_before.as_ref(),
+                            Some(reverted_planet),
+                            &mut session,
+                        )
+                        .await?;
+                    }
+                    Ok(reverted_planet)
+                }
+                .await;
+                (session, result)
+            })
+            .await?;
+
+        reverted_planet.ok_or(NotFound {
+            message: format!("Can't find a reverted planet by id: {}", &id),
+        })
     }
+}
 
-    fn get_planets_collection(&self) -> Collection<Planet> {
-        self.client
-            .database(DB_NAME)
-            .collection::<Planet>(COLLECTION_NAME)
+/// Observes durations of all commands sent by the driver, including ones of change streams
+struct CommandMetrics;
+
+impl CommandEventHandler for CommandMetrics {
+    fn handle_command_succeeded_event(&self, event: CommandSucceededEvent) {
+        observe_command(&event.command_name, event.duration);
+    }
+
+    fn handle_command_failed_event(&self, event: CommandFailedEvent) {
+        observe_command(&event.command_name, event.duration);
     }
 }
 
+fn observe_command(command_name: &str, duration: Duration) {
+    crate::metrics::MONGODB_OPERATION_DURATION_SECONDS
+        .with_label_values(&[command_name])
+        .observe(duration.as_secs_f64());
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedResumeToken {
+    // the name of the watched collection and the consumer
+    #[serde(rename = "_id")]
+    id: String,
+    token: ResumeToken,
+}
+
+pub(crate) fn is_duplicate_key_error(error: &mongodb::error::Error) -> bool {
+    match *error.kind {
+        ErrorKind::Write(WriteFailure::WriteError(ref e)) => e.code == DUPLICATE_KEY_ERROR_CODE,
+        ErrorKind::Command(ref e) => e.code == DUPLICATE_KEY_ERROR_CODE,
+        _ => false,
+    }
+}
+
+// the commit is retried as is, since a transaction can't be committed twice
+async fn commit_transaction(session: &mut ClientSession) -> mongodb::error::Result<()> {
+    let mut attempt = 1;
+    loop {
+        match session.commit_transaction().await {
+            Err(e)
+                if e.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+                    && attempt < MAX_WRITE_ATTEMPTS =>
+            {
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+fn planets_filter(planet_type: Option<PlanetType>, include_deleted: bool) -> Document {
+    let mut filter = if include_deleted {
+        Document::new()
+    } else {
+        not_deleted()
+    };
+    if let Some(pt) = planet_type {
+        filter.insert("type", pt.to_string());
+    }
+    filter
+}
+
+fn not_deleted() -> Document {
+    doc! { "deleted_at": null }
+}
+
+/// Returns the changed fields with their values before and after
+pub(crate) fn diff(before: Option<&Document>, after: Option<&Document>) -> Document {
+    let empty = Document::new();
+    let before = before.unwrap_or(&empty);
+    let after = after.unwrap_or(&empty);
+
+    let mut result = Document::new();
+    for key in before
+        .keys()
+        .chain(after.keys().filter(|k| !before.contains_key(k)))
+    {
+        let value_before = before.get(key).cloned().unwrap_or(Bson::Null);
+        let value_after = after.get(key).cloned().unwrap_or(Bson::Null);
+        if key != "_id" && value_before != value_after {
+            result.insert(key, doc! { "before": value_before, "after": value_after });
+        }
+    }
+
+    result
+}
+
 pub async fn get_image_of_planet(planet_name: &str) -> Vec<u8> {
     let filename = format!("{}.jpg", planet_name.to_lowercase());
     let image = Asset::get(&filename).expect("Failed to open image");
@@ -1,40 +1,296 @@
-use chrono::{NaiveDate, NaiveDateTime};
+use std::collections::{BTreeMap, HashMap};
+
+use async_graphql::{Enum, InputObject, SimpleObject};
+use chrono::{DateTime, NaiveDate, Utc};
+use mongodb::bson::{Bson, Document};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 
-use crate::model::{Planet, PlanetType, Satellite};
+use crate::broadcaster::ClientInfo;
+use crate::model::{
+    DependencyHealth, Planet, PlanetStats, PlanetType, Revision, RevisionOperation, Satellite,
+};
 
-#[derive(Serialize, Deserialize)]
+const KILOMETERS_IN_MILE: f32 = 1.609_344;
+const EARTH_MEAN_RADIUS_KILOMETERS: f32 = 6371.0;
+
+#[derive(Serialize, Deserialize, ToSchema)]
 pub struct PlanetDto {
     pub id: Option<String>,
     pub name: String,
     pub r#type: PlanetType,
-    pub mean_radius: f32,
+    pub mean_radius: Length,
     pub satellites: Option<Vec<SatelliteDto>>,
+    // is only filled for deleted planets and is ignored in requests
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Serialize, Deserialize)]
+/// A plain number is a value in kilometers
+#[derive(Copy, Clone, Debug, Serialize, Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum Length {
+    Kilometers(f32),
+    WithUnit { value: f32, unit: LengthUnit },
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize, ToSchema, Enum)]
+#[serde(rename_all = "snake_case")]
+pub enum LengthUnit {
+    Km,
+    Mi,
+    EarthRadius,
+}
+
+/// Unit system in which measurements are returned
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Units {
+    Metric,
+    Imperial,
+    EarthRelative,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, SimpleObject, InputObject)]
+#[graphql(name = "Satellite", input_name = "SatelliteInput")]
 pub struct SatelliteDto {
     pub name: String,
     pub first_spacecraft_landing_date: Option<NaiveDate>,
 }
 
 #[derive(Serialize)]
+pub struct PlanetComparisonDto {
+    pub reference: ComparedPlanetDto,
+    pub planets: Vec<ComparedPlanetDto>,
+}
+
+/// Ratios are relative to the reference planet; a ratio is absent if the reference value is zero
+#[derive(Serialize)]
+pub struct ComparedPlanetDto {
+    pub id: Option<String>,
+    pub name: String,
+    pub r#type: PlanetType,
+    pub mean_radius: Length,
+    pub mean_radius_ratio: Option<f32>,
+    pub satellites_count: usize,
+    pub satellites_count_ratio: Option<f32>,
+}
+
+#[derive(Serialize)]
+pub struct PlanetStatsDto {
+    pub count_by_type: BTreeMap<String, i64>,
+    pub min_mean_radius: Option<f64>,
+    pub max_mean_radius: Option<f64>,
+    pub avg_mean_radius: Option<f64>,
+    pub total_satellites: i64,
+    pub earliest_landing_date: Option<NaiveDate>,
+    pub latest_landing_date: Option<NaiveDate>,
+}
+
+#[derive(Serialize)]
+pub struct BulkResultDto {
+    pub created: usize,
+    pub updated: usize,
+    pub failed: usize,
+    pub items: Vec<BulkItemResultDto>,
+}
+
+#[derive(Serialize)]
+pub struct BulkItemResultDto {
+    pub index: usize,
+    pub status: BulkItemStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BulkItemStatus {
+    Created,
+    Updated,
+    Failed,
+}
+
+/// Flat representation of a planet for CSV; satellites are separated by `;`, and the first
+/// spacecraft landing date follows the name of a satellite after `:`, e.g. `Moon:1959-09-13`
+#[derive(Serialize, Deserialize)]
+pub struct PlanetCsvRecord {
+    pub id: String,
+    pub name: String,
+    pub r#type: PlanetType,
+    pub mean_radius: f32,
+    pub satellites: String,
+}
+
+/// Representation of a planet in API v2: measurements always have units, satellites are never null
+/// and related resources are linked
+#[derive(Serialize)]
+pub struct PlanetV2Dto {
+    pub id: String,
+    pub name: String,
+    pub r#type: PlanetType,
+    pub mean_radius: Length,
+    pub satellites: Vec<SatelliteDto>,
+    pub satellites_count: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<DateTime<Utc>>,
+    pub links: PlanetLinksDto,
+}
+
+#[derive(Serialize)]
+pub struct PlanetLinksDto {
+    #[serde(rename = "self")]
+    pub self_link: String,
+    pub image: String,
+    pub history: String,
+}
+
+/// Every response body of API v2 is wrapped into an envelope
+#[derive(Serialize)]
+pub struct EnvelopeDto<T> {
+    pub data: T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub meta: Option<MetaDto>,
+}
+
+#[derive(Serialize)]
+pub struct MetaDto {
+    pub count: usize,
+}
+
+#[derive(Serialize)]
+pub struct HealthDto {
+    pub status: HealthStatus,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub dependencies: BTreeMap<&'static str, DependencyHealthDto>,
+}
+
+#[derive(Serialize)]
+pub struct DependencyHealthDto {
+    pub status: HealthStatus,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Up,
+    Down,
+}
+
+#[derive(Serialize)]
+pub struct RevisionDto {
+    pub revision: i64,
+    pub operation: RevisionOperation,
+    pub actor: String,
+    pub timestamp: DateTime<Utc>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub diff: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub struct FlushCacheDto {
+    pub deleted: u64,
+}
+
+#[derive(Serialize)]
+pub struct RateLimitDto {
+    pub client: String,
+    /// Requests made in the current minute
+    pub requests: u64,
+    pub limit: u64,
+}
+
+#[derive(Serialize)]
+pub struct SseClientsDto {
+    pub clients: Vec<SseClientDto>,
+    pub graphql_subscribers: usize,
+}
+
+#[derive(Serialize)]
+pub struct SseClientDto {
+    pub address: Option<String>,
+    pub connected_at: DateTime<Utc>,
+    pub queued_messages: usize,
+}
+
+#[derive(Serialize, Deserialize)]
 pub struct PlanetMessage {
     pub id: String,
     pub name: String,
     pub r#type: PlanetType,
+    /// W3C trace context of the request that created the planet
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub trace_context: HashMap<String, String>,
+    /// ID of the entry of Redis stream of planet events; is added when the event is published
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stream_id: Option<String>,
 }
 
 impl From<Planet> for PlanetDto {
     fn from(source: Planet) -> Self {
+        PlanetDto::with_units(source, None)
+    }
+}
+
+impl PlanetDto {
+    /// Measurements are plain numbers in metric units if no unit system is specified
+    pub fn with_units(source: Planet, units: Option<Units>) -> Self {
         PlanetDto {
             id: source.id.map(|id| id.to_string()),
             name: source.name,
             r#type: source.r#type,
-            mean_radius: source.mean_radius,
+            mean_radius: Length::from_kilometers(source.mean_radius, units),
             satellites: source
                 .satellites
                 .map(|satellites| satellites.into_iter().map(SatelliteDto::from).collect()),
+            deleted_at: source
+                .deleted_at
+                .map(|d| to_date_time(d.timestamp_millis())),
+        }
+    }
+}
+
+impl Length {
+    pub fn from_kilometers(kilometers: f32, units: Option<Units>) -> Self {
+        let unit = match units {
+            None => return Length::Kilometers(kilometers),
+            Some(Units::Metric) => LengthUnit::Km,
+            Some(Units::Imperial) => LengthUnit::Mi,
+            Some(Units::EarthRelative) => LengthUnit::EarthRadius,
+        };
+
+        Length::WithUnit {
+            value: kilometers / unit.kilometers(),
+            unit,
+        }
+    }
+
+    pub fn to_kilometers(self) -> f32 {
+        match self {
+            Length::Kilometers(kilometers) => kilometers,
+            Length::WithUnit { value, unit } => value * unit.kilometers(),
+        }
+    }
+
+    pub fn to_unit(self, unit: LengthUnit) -> f32 {
+        self.to_kilometers() / unit.kilometers()
+    }
+}
+
+impl LengthUnit {
+    fn kilometers(&self) -> f32 {
+        match self {
+            LengthUnit::Km => 1.0,
+            LengthUnit::Mi => KILOMETERS_IN_MILE,
+            LengthUnit::EarthRadius => EARTH_MEAN_RADIUS_KILOMETERS,
         }
     }
 }
@@ -43,13 +299,240 @@ impl From<Satellite> for SatelliteDto {
     fn from(source: Satellite) -> Self {
         SatelliteDto {
             name: source.name,
-            first_spacecraft_landing_date: source
-                .first_spacecraft_landing_date
-                .map(|d| NaiveDateTime::from_timestamp(d.timestamp_millis() / 1000, 0).date()),
+            first_spacecraft_landing_date: source.first_spacecraft_landing_date.map(to_naive_date),
+        }
+    }
+}
+
+impl PlanetComparisonDto {
+    pub fn new(reference: Planet, planets: Vec<Planet>, units: Option<Units>) -> Self {
+        let compare = |planet: &Planet| ComparedPlanetDto {
+            id: planet.id.map(|id| id.to_string()),
+            name: planet.name.clone(),
+            r#type: planet.r#type,
+            mean_radius: Length::from_kilometers(planet.mean_radius, units),
+            mean_radius_ratio: ratio(planet.mean_radius, reference.mean_radius),
+            satellites_count: satellites_count(planet),
+            satellites_count_ratio: ratio(
+                satellites_count(planet) as f32,
+                satellites_count(&reference) as f32,
+            ),
+        };
+
+        PlanetComparisonDto {
+            reference: compare(&reference),
+            planets: planets.iter().map(compare).collect(),
+        }
+    }
+}
+
+fn satellites_count(planet: &Planet) -> usize {
+    planet.satellites.as_ref().map_or(0, Vec::len)
+}
+
+fn ratio(value: f32, reference_value: f32) -> Option<f32> {
+    if reference_value == 0.0 {
+        None
+    } else {
+        Some(value / reference_value)
+    }
+}
+
+impl From<PlanetStats> for PlanetStatsDto {
+    fn from(source: PlanetStats) -> Self {
+        PlanetStatsDto {
+            count_by_type: source
+                .count_by_type
+                .into_iter()
+                .map(|type_count| (type_count.r#type.to_string(), type_count.count))
+                .collect(),
+            min_mean_radius: source.min_mean_radius,
+            max_mean_radius: source.max_mean_radius,
+            avg_mean_radius: source.avg_mean_radius,
+            total_satellites: source.total_satellites,
+            earliest_landing_date: source.earliest_landing_date.map(to_naive_date),
+            latest_landing_date: source.latest_landing_date.map(to_naive_date),
+        }
+    }
+}
+
+impl From<Vec<BulkItemResultDto>> for BulkResultDto {
+    fn from(items: Vec<BulkItemResultDto>) -> Self {
+        let count = |status| items.iter().filter(|item| item.status == status).count();
+
+        BulkResultDto {
+            created: count(BulkItemStatus::Created),
+            updated: count(BulkItemStatus::Updated),
+            failed: count(BulkItemStatus::Failed),
+            items,
+        }
+    }
+}
+
+impl From<Planet> for PlanetCsvRecord {
+    fn from(source: Planet) -> Self {
+        PlanetCsvRecord {
+            id: source.id.map(|id| id.to_string()).unwrap_or_default(),
+            name: source.name,
+            r#type: source.r#type,
+            mean_radius: source.mean_radius,
+            satellites: source
+                .satellites
+                .unwrap_or_default()
+                .into_iter()
+                .map(|satellite| match satellite.first_spacecraft_landing_date {
+                    Some(date) => format!("{}:{}", satellite.name, to_naive_date(date)),
+                    None => satellite.name,
+                })
+                .collect::<Vec<_>>()
+                .join(";"),
+        }
+    }
+}
+
+impl From<PlanetCsvRecord> for PlanetDto {
+    fn from(source: PlanetCsvRecord) -> Self {
+        let satellites: Vec<SatelliteDto> = source
+            .satellites
+            .split(';')
+            .filter(|satellite| !satellite.is_empty())
+            .map(|satellite| {
+                // a name that merely contains `:` isn't split
+                let name_and_date = satellite.rsplit_once(':').and_then(|(name, date)| {
+                    date.parse::<NaiveDate>().ok().map(|date| (name, date))
+                });
+                match name_and_date {
+                    Some((name, date)) => SatelliteDto {
+                        name: name.to_string(),
+                        first_spacecraft_landing_date: Some(date),
+                    },
+                    None => SatelliteDto {
+                        name: satellite.to_string(),
+                        first_spacecraft_landing_date: None,
+                    },
+                }
+            })
+            .collect();
+
+        PlanetDto {
+            id: Some(source.id).filter(|id| !id.is_empty()),
+            name: source.name,
+            r#type: source.r#type,
+            mean_radius: Length::Kilometers(source.mean_radius),
+            satellites: Some(satellites).filter(|satellites| !satellites.is_empty()),
+            deleted_at: None,
+        }
+    }
+}
+
+impl PlanetV2Dto {
+    /// Metric units are used if no unit system is specified
+    pub fn new(source: Planet, units: Option<Units>) -> Self {
+        let planet = PlanetDto::with_units(source, Some(units.unwrap_or(Units::Metric)));
+        let id = planet.id.expect("Planet.id is not specified");
+        let satellites = planet.satellites.unwrap_or_default();
+
+        PlanetV2Dto {
+            links: PlanetLinksDto {
+                self_link: format!("/v2/planets/{}", id),
+                image: format!("/v2/planets/{}/image", id),
+                history: format!("/v2/planets/{}/history", id),
+            },
+            id,
+            name: planet.name,
+            r#type: planet.r#type,
+            mean_radius: planet.mean_radius,
+            satellites_count: satellites.len(),
+            satellites,
+            deleted_at: planet.deleted_at,
+        }
+    }
+}
+
+impl<T> EnvelopeDto<T> {
+    pub fn new(data: T) -> Self {
+        EnvelopeDto { data, meta: None }
+    }
+}
+
+impl<T> EnvelopeDto<Vec<T>> {
+    pub fn list(data: Vec<T>) -> Self {
+        EnvelopeDto {
+            meta: Some(MetaDto { count: data.len() }),
+            data,
+        }
+    }
+}
+
+impl HealthDto {
+    pub fn live() -> Self {
+        HealthDto {
+            status: HealthStatus::Up,
+            dependencies: BTreeMap::new(),
+        }
+    }
+}
+
+impl From<Vec<DependencyHealth>> for HealthDto {
+    fn from(source: Vec<DependencyHealth>) -> Self {
+        let dependencies: BTreeMap<&'static str, DependencyHealthDto> = source
+            .into_iter()
+            .map(|health| {
+                let dto = DependencyHealthDto {
+                    status: if health.error.is_none() {
+                        HealthStatus::Up
+                    } else {
+                        HealthStatus::Down
+                    },
+                    latency_ms: health.latency.as_secs_f64() * 1000.0,
+                    error: health.error,
+                };
+                (health.dependency, dto)
+            })
+            .collect();
+
+        let status = if dependencies
+            .values()
+            .all(|dependency| dependency.status == HealthStatus::Up)
+        {
+            HealthStatus::Up
+        } else {
+            HealthStatus::Down
+        };
+
+        HealthDto {
+            status,
+            dependencies,
+        }
+    }
+}
+
+impl From<Revision> for RevisionDto {
+    fn from(source: Revision) -> Self {
+        RevisionDto {
+            revision: source.revision,
+            operation: source.operation,
+            actor: source.actor,
+            timestamp: to_date_time(source.timestamp.timestamp_millis()),
+            before: source.before.map(document_to_json),
+            after: source.after.map(document_to_json),
+            diff: document_to_json(source.diff),
         }
     }
 }
 
+fn to_naive_date(date: mongodb::bson::DateTime) -> NaiveDate {
+    to_date_time(date.timestamp_millis()).date_naive()
+}
+
+fn to_date_time(timestamp_millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(timestamp_millis).expect("Timestamp is out of range")
+}
+
+fn document_to_json(document: Document) -> serde_json::Value {
+    Bson::Document(document).into_relaxed_extjson()
+}
+
 impl From<&Planet> for PlanetMessage {
     fn from(source: &Planet) -> Self {
         PlanetMessage {
@@ -59,6 +542,18 @@ impl From<&Planet> for PlanetMessage {
                 .expect("Planet.id is not specified"),
             name: source.name.clone(),
             r#type: source.r#type,
+            trace_context: HashMap::new(),
+            stream_id: None,
+        }
+    }
+}
+
+impl From<ClientInfo> for SseClientDto {
+    fn from(source: ClientInfo) -> Self {
+        SseClientDto {
+            address: source.address,
+            connected_at: source.connected_at,
+            queued_messages: source.queued_messages,
         }
     }
 }
@@ -1,11 +1,19 @@
 use actix_web::error::ResponseError;
-use actix_web::http::header::ContentType;
+use actix_web::http::header;
 use actix_web::http::StatusCode;
 use actix_web::{HttpResponse, HttpResponseBuilder};
+use chrono::{Timelike, Utc};
 use derive_more::{Display, Error};
-use log::error;
 use redis::RedisError;
 use serde::Serialize;
+use tracing::error;
+use utoipa::ToSchema;
+
+use crate::{db, request_id};
+
+pub const PROBLEM_JSON_CONTENT_TYPE: &str = "application/problem+json";
+const PROBLEM_TYPE: &str = "about:blank";
+pub(crate) const DUPLICATE_NAME_MESSAGE: &str = "A planet with the same name already exists";
 
 #[derive(Debug, Display, Error)]
 pub enum CustomError {
@@ -21,6 +29,30 @@ pub enum CustomError {
     NotFound {
         message: String,
     },
+    #[display(fmt = message)]
+    BadRequest {
+        message: String,
+    },
+    #[display(fmt = message)]
+    Conflict {
+        message: String,
+    },
+    #[display(fmt = message)]
+    NotAcceptable {
+        message: String,
+    },
+    #[display(fmt = message)]
+    UnsupportedMediaType {
+        message: String,
+    },
+    #[display(fmt = message)]
+    Unauthorized {
+        message: String,
+    },
+    #[display(fmt = message)]
+    Forbidden {
+        message: String,
+    },
     InternalError,
     #[display(
         fmt = "Actual requests count: {}. Permitted requests count: {}",
@@ -39,6 +71,12 @@ impl CustomError {
             Self::MongoDbError { message: _ } => "MongoDB error",
             Self::RedisError { message: _ } => "Redis error",
             Self::NotFound { message: _ } => "Resource not found",
+            Self::BadRequest { message: _ } => "Bad request",
+            Self::Conflict { message: _ } => "Conflict",
+            Self::NotAcceptable { message: _ } => "Not acceptable",
+            Self::UnsupportedMediaType { message: _ } => "Unsupported media type",
+            Self::Unauthorized { message: _ } => "Unauthorized",
+            Self::Forbidden { message: _ } => "Forbidden",
             Self::InternalError => "Internal error",
             Self::TooManyRequests {
                 actual_count: _,
@@ -56,6 +94,12 @@ impl ResponseError for CustomError {
             CustomError::MongoDbError { message: _ } => StatusCode::INTERNAL_SERVER_ERROR,
             CustomError::RedisError { message: _ } => StatusCode::INTERNAL_SERVER_ERROR,
             CustomError::NotFound { message: _ } => StatusCode::NOT_FOUND,
+            CustomError::BadRequest { message: _ } => StatusCode::BAD_REQUEST,
+            CustomError::Conflict { message: _ } => StatusCode::CONFLICT,
+            CustomError::NotAcceptable { message: _ } => StatusCode::NOT_ACCEPTABLE,
+            CustomError::UnsupportedMediaType { message: _ } => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            CustomError::Unauthorized { message: _ } => StatusCode::UNAUTHORIZED,
+            CustomError::Forbidden { message: _ } => StatusCode::FORBIDDEN,
             CustomError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
             CustomError::TooManyRequests {
                 actual_count: _,
@@ -65,27 +109,60 @@ impl ResponseError for CustomError {
     }
 
     fn error_response(&self) -> HttpResponse {
-        error!("Error: {}", self.to_string());
+        error!(error = %self.name(), "Error: {}", self.to_string());
 
+        let status = self.status_code();
         let error_response = ErrorResponse {
-            error: self.name(),
-            message: self.to_string(),
+            r#type: String::from(PROBLEM_TYPE),
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            request_id: request_id::current(),
         };
 
-        HttpResponseBuilder::new(self.status_code())
-            .content_type(ContentType::json())
+        let mut response = HttpResponseBuilder::new(status);
+        match self {
+            // requests are counted per calendar minute
+            Self::TooManyRequests { .. } => {
+                response.insert_header((header::RETRY_AFTER, 60 - Utc::now().second()));
+            }
+            Self::Unauthorized { .. } => {
+                response.insert_header((header::WWW_AUTHENTICATE, "Basic realm=\"admin\""));
+            }
+            _ => {}
+        }
+
+        response
+            .content_type(PROBLEM_JSON_CONTENT_TYPE)
             .body(serde_json::to_string(&error_response).expect("Can't serialize error response"))
     }
 }
 
-#[derive(Serialize)]
-struct ErrorResponse {
-    error: String,
-    message: String,
+/// Problem details as defined by RFC 7807
+#[derive(Serialize, ToSchema)]
+pub struct ErrorResponse {
+    /// Problems are distinguished by status codes, so the type is always `about:blank`
+    #[schema(example = "about:blank")]
+    r#type: String,
+    /// Reason phrase of the status code
+    title: String,
+    status: u16,
+    /// Explanation of the specific occurrence of the problem
+    detail: String,
+    /// Id of the request that can be used to find it in logs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
 }
 
+// names of planets that aren't deleted are the only unique keys a client can violate, since
+// revision numbers are retried
 impl From<mongodb::error::Error> for CustomError {
     fn from(source: mongodb::error::Error) -> Self {
+        if db::is_duplicate_key_error(&source) {
+            return Self::Conflict {
+                message: String::from(DUPLICATE_NAME_MESSAGE),
+            };
+        }
         Self::MongoDbError {
             message: source.to_string(),
         }
@@ -129,3 +206,9 @@ impl From<serde_json::Error> for CustomError {
         Self::InternalError
     }
 }
+
+impl From<csv::Error> for CustomError {
+    fn from(_source: csv::Error) -> Self {
+        Self::InternalError
+    }
+}
@@ -0,0 +1,299 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use actix_web::web::Data;
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::{Context, InputObject, Object, Schema, SimpleObject, Subscription, ID};
+use chrono::{DateTime, Utc};
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tracing::error;
+
+use crate::broadcaster::Broadcaster;
+use crate::dto::{Length, LengthUnit, PlanetDto, PlanetMessage, SatelliteDto};
+use crate::errors::CustomError;
+use crate::model::{Planet, PlanetType};
+use crate::services::PlanetService;
+
+const DEFAULT_PAGE_SIZE: u32 = 20;
+const MAX_PAGE_SIZE: u32 = 100;
+
+pub type PlanetSchema = Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
+
+pub fn create_schema(
+    planet_service: Data<PlanetService>,
+    broadcaster: Data<Mutex<Broadcaster>>,
+    enable_writing_handlers: bool,
+) -> PlanetSchema {
+    Schema::build(QueryRoot, MutationRoot, SubscriptionRoot)
+        .data(planet_service)
+        .data(broadcaster)
+        .data(WritingHandlers(enable_writing_handlers))
+        .finish()
+}
+
+/// Should be created for every request so that planets are batched within the request only
+pub fn create_planet_loader(planet_service: Data<PlanetService>) -> DataLoader<PlanetLoader> {
+    DataLoader::new(PlanetLoader { planet_service }, tokio::spawn)
+}
+
+/// Is taken from `X-Actor` header like in REST handlers
+pub struct Actor(pub String);
+
+/// Whether the request is authorized to use admin API; deleted planets are visible to admins only
+pub struct AdminAccess(pub bool);
+
+struct WritingHandlers(bool);
+
+pub struct PlanetLoader {
+    planet_service: Data<PlanetService>,
+}
+
+impl Loader<String> for PlanetLoader {
+    type Value = Planet;
+    type Error = Arc<CustomError>;
+
+    async fn load(&self, keys: &[String]) -> Result<HashMap<String, Planet>, Self::Error> {
+        self.planet_service
+            .get_planets_by_ids(keys)
+            .await
+            .map_err(Arc::new)
+    }
+}
+
+#[derive(Default, InputObject)]
+pub struct PlanetFilter {
+    r#type: Option<PlanetType>,
+    #[graphql(default)]
+    include_deleted: bool,
+}
+
+#[derive(InputObject)]
+pub struct Page {
+    #[graphql(default)]
+    offset: u32,
+    #[graphql(default_with = "DEFAULT_PAGE_SIZE")]
+    limit: u32,
+}
+
+impl Default for Page {
+    fn default() -> Self {
+        Page {
+            offset: 0,
+            limit: DEFAULT_PAGE_SIZE,
+        }
+    }
+}
+
+#[derive(InputObject)]
+pub struct PlanetInput {
+    name: String,
+    r#type: PlanetType,
+    /// In units of `meanRadiusUnit`
+    mean_radius: f32,
+    #[graphql(default_with = "LengthUnit::Km")]
+    mean_radius_unit: LengthUnit,
+    satellites: Option<Vec<SatelliteDto>>,
+}
+
+impl TryFrom<PlanetInput> for Planet {
+    type Error = CustomError;
+
+    fn try_from(source: PlanetInput) -> Result<Self, Self::Error> {
+        Planet::try_from(PlanetDto {
+            id: None,
+            name: source.name,
+            r#type: source.r#type,
+            mean_radius: Length::WithUnit {
+                value: source.mean_radius,
+                unit: source.mean_radius_unit,
+            },
+            satellites: source.satellites,
+            deleted_at: None,
+        })
+    }
+}
+
+pub struct PlanetObject(PlanetDto);
+
+#[Object(name = "Planet")]
+impl PlanetObject {
+    async fn id(&self) -> Option<ID> {
+        self.0.id.clone().map(ID)
+    }
+
+    async fn name(&self) -> &str {
+        &self.0.name
+    }
+
+    async fn r#type(&self) -> PlanetType {
+        self.0.r#type
+    }
+
+    /// In kilometers unless another unit is specified
+    async fn mean_radius(
+        &self,
+        #[graphql(default_with = "LengthUnit::Km")] unit: LengthUnit,
+    ) -> f32 {
+        self.0.mean_radius.to_unit(unit)
+    }
+
+    async fn satellites(&self) -> &[SatelliteDto] {
+        self.0.satellites.as_deref().unwrap_or_default()
+    }
+
+    async fn deleted_at(&self) -> Option<DateTime<Utc>> {
+        self.0.deleted_at
+    }
+}
+
+impl From<Planet> for PlanetObject {
+    fn from(source: Planet) -> Self {
+        PlanetObject(PlanetDto::from(source))
+    }
+}
+
+#[derive(SimpleObject)]
+pub struct CreatedPlanet {
+    id: ID,
+    name: String,
+    r#type: PlanetType,
+}
+
+impl From<PlanetMessage> for CreatedPlanet {
+    fn from(source: PlanetMessage) -> Self {
+        CreatedPlanet {
+            id: ID(source.id),
+            name: source.name,
+            r#type: source.r#type,
+        }
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    async fn planets(
+        &self,
+        ctx: &Context<'_>,
+        #[graphql(default)] filter: PlanetFilter,
+        #[graphql(default)] page: Page,
+    ) -> async_graphql::Result<Vec<PlanetObject>> {
+        assert_can_include_deleted(ctx, filter.include_deleted)?;
+        let planets = ctx
+            .data::<Data<PlanetService>>()?
+            .get_planets_page(
+                filter.r#type,
+                filter.include_deleted,
+                page.offset.into(),
+                cmp::min(page.limit, MAX_PAGE_SIZE).into(),
+            )
+            .await?;
+
+        Ok(planets.into_iter().map(PlanetObject::from).collect())
+    }
+
+    /// Planets requested in the same query are loaded in one batch
+    async fn planet(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+    ) -> async_graphql::Result<Option<PlanetObject>> {
+        let planet = ctx
+            .data::<DataLoader<PlanetLoader>>()?
+            .load_one(id.to_string())
+            .await?;
+
+        Ok(planet.map(PlanetObject::from))
+    }
+}
+
+pub struct MutationRoot;
+
+#[Object]
+impl MutationRoot {
+    async fn create_planet(
+        &self,
+        ctx: &Context<'_>,
+        planet: PlanetInput,
+    ) -> async_graphql::Result<PlanetObject> {
+        assert_writing_enabled(ctx)?;
+        let planet = ctx
+            .data::<Data<PlanetService>>()?
+            .create_planet(planet.try_into()?, &ctx.data::<Actor>()?.0)
+            .await?;
+
+        Ok(planet.into())
+    }
+
+    async fn update_planet(
+        &self,
+        ctx: &Context<'_>,
+        id: ID,
+        planet: PlanetInput,
+    ) -> async_graphql::Result<PlanetObject> {
+        assert_writing_enabled(ctx)?;
+        let planet = ctx
+            .data::<Data<PlanetService>>()?
+            .update_planet(&id, planet.try_into()?, &ctx.data::<Actor>()?.0)
+            .await?;
+
+        Ok(planet.into())
+    }
+
+    async fn delete_planet(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<bool> {
+        assert_writing_enabled(ctx)?;
+        ctx.data::<Data<PlanetService>>()?
+            .delete_planet(&id, &ctx.data::<Actor>()?.0)
+            .await?;
+
+        Ok(true)
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    async fn planet_created(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = CreatedPlanet>> {
+        let rx = ctx
+            .data::<Data<Mutex<Broadcaster>>>()?
+            .lock()
+            .expect("Can't lock broadcaster")
+            .new_subscriber();
+
+        Ok(ReceiverStream::new(rx).filter_map(|payload| {
+            match serde_json::from_str::<PlanetMessage>(&payload) {
+                Ok(message) => Some(CreatedPlanet::from(message)),
+                Err(e) => {
+                    error!("Can't parse planet event: {}", e);
+                    None
+                }
+            }
+        }))
+    }
+}
+
+fn assert_writing_enabled(ctx: &Context<'_>) -> async_graphql::Result<()> {
+    if ctx.data::<WritingHandlers>()?.0 {
+        Ok(())
+    } else {
+        Err("Writing handlers are disabled".into())
+    }
+}
+
+fn assert_can_include_deleted(
+    ctx: &Context<'_>,
+    include_deleted: bool,
+) -> async_graphql::Result<()> {
+    if !include_deleted || ctx.data::<AdminAccess>()?.0 {
+        Ok(())
+    } else {
+        Err("Deleted planets are visible to admins only".into())
+    }
+}
@@ -1,21 +1,105 @@
-use actix_web::http::header::{self, ContentType};
+use actix_web::http::header::{self, ContentType, Header};
 use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
 use actix_web::{web, HttpRequest, HttpResponse};
+use async_graphql::http::GraphiQLSource;
 use prometheus::{Encoder, TextEncoder};
 use serde::Deserialize;
 
+use crate::admin::{self, AdminApiAuth, AdminAuth};
 use crate::broadcaster::Broadcaster;
-use crate::dto::PlanetDto;
-use crate::errors::CustomError;
-use crate::model::PlanetType;
-use crate::services::{PlanetService, RateLimitingService};
+use crate::dto::{
+    BulkItemResultDto, BulkItemStatus, BulkResultDto, EnvelopeDto, FlushCacheDto, HealthDto,
+    HealthStatus, PlanetComparisonDto, PlanetCsvRecord, PlanetDto, PlanetStatsDto, PlanetV2Dto,
+    RateLimitDto, RevisionDto, SseClientDto, SseClientsDto, Units,
+};
+use crate::errors::{CustomError, ErrorResponse};
+use crate::graphql::{self, Actor, AdminAccess, PlanetSchema};
+use crate::model::{Planet, PlanetType};
+use crate::negotiation::{self, PlanetPayload};
+use crate::openapi::ApiDoc;
+use crate::openmetrics;
+use crate::services::{HealthService, PlanetService, RateLimitingService};
+use crate::storage::PlanetStream;
+use std::net::IpAddr;
 use std::sync::Mutex;
+use tokio_stream::{Stream, StreamExt};
+use utoipa::{IntoParams, OpenApi};
 
-#[derive(Debug, Deserialize)]
+const ACTOR_HEADER_NAME: &str = "X-Actor";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+const CSV_HEADER_ROW: &str = "id,name,type,mean_radius,satellites\n";
+const ANONYMOUS_ACTOR: &str = "anonymous";
+const MAX_COMPARED_PLANETS: usize = 10;
+const OPENMETRICS_MIME_TYPE: &str = "application/openmetrics-text";
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 pub struct GetPlanetsQueryParams {
     r#type: Option<PlanetType>,
+    #[serde(default)]
+    include_deleted: bool,
+    // makes the response a chunked JSON array
+    #[serde(default)]
+    stream: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ExportPlanetsQueryParams {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+#[derive(Debug, Default, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    #[default]
+    Ndjson,
+    Csv,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ComparePlanetsQueryParams {
+    // comma-separated
+    ids: String,
+    reference: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FlushCacheQueryParams {
+    prefix: Option<String>,
 }
 
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GetPlanetQueryParams {
+    #[serde(default)]
+    include_deleted: bool,
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/planets",
+    tag = "planets",
+    params(GetPlanetsQueryParams, ("units" = Option<Units>, Query)),
+    responses(
+        (status = 200, description = "List of planets", content(
+            (Vec<PlanetDto> = "application/json"),
+            (Vec<PlanetDto> = "application/cbor"),
+            (Vec<PlanetDto> = "application/msgpack"),
+            (String = "text/csv"),
+            (PlanetDto = "application/x-ndjson"),
+        )),
+        (status = 400, description = "Invalid query parameters", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 401, description = "Invalid admin credentials", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 403, description = "Deleted planets are requested without admin access", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 406, description = "Unsupported format is requested", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 429, description = "Rate limit is exceeded", body = ErrorResponse, content_type = "application/problem+json", headers(
+            ("Retry-After" = u32, description = "Seconds until the rate limit is reset"),
+        )),
+        (status = 500, description = "MongoDB, Redis or internal error", body = ErrorResponse, content_type = "application/problem+json"),
+    )
+)]
 pub async fn get_planets(
     req: HttpRequest,
     web::Query(query_params): web::Query<GetPlanetsQueryParams>,
@@ -27,52 +111,461 @@ pub async fn get_planets(
         .assert_rate_limit_not_exceeded(get_ip_addr(&req)?)
         .await?;
 
-    let planets = planet_service.get_planets(query_params.r#type).await?;
-    Ok(HttpResponse::Ok().json(planets.into_iter().map(PlanetDto::from).collect::<Vec<_>>()))
+    assert_can_include_deleted(&req, query_params.include_deleted)?;
+
+    let is_ndjson = accepts(&req, NDJSON_CONTENT_TYPE);
+    if is_ndjson || query_params.stream {
+        let units = negotiation::get_units(&req)?;
+        let planets = planet_service
+            .get_planets_stream(query_params.r#type, query_params.include_deleted)
+            .await?;
+
+        return Ok(if is_ndjson {
+            HttpResponse::Ok()
+                .insert_header((header::CONTENT_TYPE, NDJSON_CONTENT_TYPE))
+                .streaming(to_ndjson_stream(planets, units))
+        } else {
+            HttpResponse::Ok()
+                .content_type(ContentType::json())
+                .streaming(to_json_array_stream(planets, units))
+        });
+    }
+
+    let planets = planet_service
+        .get_planets(query_params.r#type, query_params.include_deleted)
+        .await?;
+    negotiation::planet_list_response(&req, StatusCode::OK, planets)
 }
 
+#[utoipa::path(
+    post,
+    path = "/v1/planets",
+    tag = "planets",
+    params(
+        ("X-Actor" = Option<String>, Header,
+            description = "Actor recorded in the revision history; it isn't verified"
+        ),
+    ),
+    request_body(content(
+        (PlanetDto = "application/json"),
+        (PlanetDto = "application/cbor"),
+        (PlanetDto = "application/msgpack"),
+        (String = "text/csv"),
+    )),
+    responses(
+        (status = 200, description = "Created planet", body = PlanetDto),
+        (status = 400, description = "Invalid planet", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 409, description = "A planet with the same name already exists", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 406, description = "Unsupported format is requested", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 415, description = "Unsupported format of the planet", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 500, description = "MongoDB, Redis or internal error", body = ErrorResponse, content_type = "application/problem+json"),
+    )
+)]
 pub async fn create_planet(
-    planet_dto: web::Json<PlanetDto>,
+    req: HttpRequest,
+    planet: PlanetPayload,
     planet_service: web::Data<PlanetService>,
 ) -> Result<HttpResponse, CustomError> {
     let planet = planet_service
-        .create_planet(planet_dto.into_inner().into())
+        .create_planet(planet.into_inner(), &get_actor(&req))
+        .await?;
+
+    negotiation::planet_response(&req, StatusCode::OK, planet)
+}
+
+pub async fn bulk_upsert_planets(
+    req: HttpRequest,
+    body: web::Bytes,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let is_ndjson = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(NDJSON_CONTENT_TYPE));
+
+    let items: Vec<serde_json::Value> = if is_ndjson {
+        body.split(|b| *b == b'\n')
+            .filter(|line| !line.iter().all(u8::is_ascii_whitespace))
+            .map(serde_json::from_slice)
+            .collect::<Result<_, _>>()
+    } else {
+        serde_json::from_slice(&body)
+    }
+    .map_err(|e| CustomError::BadRequest {
+        message: format!("Can't parse planets: {}", e),
+    })?;
+
+    let actor = get_actor(&req);
+    let mut results = Vec::with_capacity(items.len());
+    for (index, item) in items.into_iter().enumerate() {
+        let result = match serde_json::from_value::<PlanetDto>(item) {
+            Ok(mut planet_dto) => {
+                // planets are matched by name, so ids from the request are ignored
+                planet_dto.id = None;
+                let name = planet_dto.name.clone();
+                let result = async {
+                    let planet = Planet::try_from(planet_dto)?;
+                    planet_service.upsert_planet_by_name(planet, &actor).await
+                }
+                .await;
+                match result {
+                    Ok((planet, is_created)) => BulkItemResultDto {
+                        index,
+                        status: if is_created {
+                            BulkItemStatus::Created
+                        } else {
+                            BulkItemStatus::Updated
+                        },
+                        id: planet.id.map(|id| id.to_string()),
+                        name: Some(name),
+                        error: None,
+                    },
+                    Err(e) => BulkItemResultDto {
+                        index,
+                        status: BulkItemStatus::Failed,
+                        id: None,
+                        name: Some(name),
+                        error: Some(e.to_string()),
+                    },
+                }
+            }
+            Err(e) => BulkItemResultDto {
+                index,
+                status: BulkItemStatus::Failed,
+                id: None,
+                name: None,
+                error: Some(e.to_string()),
+            },
+        };
+        results.push(result);
+    }
+
+    Ok(HttpResponse::Ok().json(BulkResultDto::from(results)))
+}
+
+pub async fn export_planets(
+    req: HttpRequest,
+    web::Query(query_params): web::Query<ExportPlanetsQueryParams>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let units = negotiation::get_units(&req)?;
+    if query_params.format == ExportFormat::Csv {
+        negotiation::assert_csv_units(units)?;
+    }
+    let planets = planet_service.get_planets_stream(None, false).await?;
+
+    let response = match query_params.format {
+        ExportFormat::Ndjson => HttpResponse::Ok()
+            .insert_header((header::CONTENT_TYPE, NDJSON_CONTENT_TYPE))
+            .streaming(to_ndjson_stream(planets, units)),
+        ExportFormat::Csv => {
+            let header_row = tokio_stream::once(Ok(Bytes::from_static(CSV_HEADER_ROW.as_bytes())));
+            let rows = planets.map(|planet| {
+                let mut writer = csv::WriterBuilder::new()
+                    .has_headers(false)
+                    .from_writer(vec![]);
+                writer.serialize(PlanetCsvRecord::from(planet?))?;
+                let row = writer
+                    .into_inner()
+                    .map_err(|_| CustomError::InternalError)?;
+                Ok::<_, CustomError>(Bytes::from(row))
+            });
+
+            HttpResponse::Ok()
+                .insert_header(header::ContentType(mime::TEXT_CSV))
+                .streaming(header_row.chain(rows))
+        }
+    };
+
+    Ok(response)
+}
+
+pub async fn compare_planets(
+    req: HttpRequest,
+    web::Query(query_params): web::Query<ComparePlanetsQueryParams>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let planet_ids: Vec<String> = query_params
+        .ids
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(String::from)
+        .collect();
+    if planet_ids.is_empty() || planet_ids.len() > MAX_COMPARED_PLANETS {
+        return Err(CustomError::BadRequest {
+            message: format!(
+                "From 1 to {} planet ids should be specified",
+                MAX_COMPARED_PLANETS
+            ),
+        });
+    }
+
+    let units = negotiation::get_units(&req)?;
+    let (reference_planet, planets) = planet_service
+        .compare_planets(&planet_ids, query_params.reference.as_deref())
         .await?;
 
-    Ok(HttpResponse::Ok().json(PlanetDto::from(planet)))
+    Ok(HttpResponse::Ok().json(PlanetComparisonDto::new(reference_planet, planets, units)))
 }
 
+pub async fn get_planet_stats(
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let stats = planet_service.get_planet_stats().await?;
+    Ok(HttpResponse::Ok().json(PlanetStatsDto::from(stats)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/planets/{planet_id}",
+    tag = "planets",
+    params(
+        ("planet_id" = String, Path),
+        GetPlanetQueryParams,
+        ("units" = Option<Units>, Query),
+    ),
+    responses(
+        (status = 200, description = "Planet", body = PlanetDto),
+        (status = 400, description = "Invalid query parameters", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 401, description = "Invalid admin credentials", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 403, description = "A deleted planet is requested without admin access", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "Planet isn't found", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 406, description = "Unsupported format is requested", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 500, description = "MongoDB, Redis or internal error", body = ErrorResponse, content_type = "application/problem+json"),
+    )
+)]
 pub async fn get_planet(
+    req: HttpRequest,
     planet_id: web::Path<String>,
+    web::Query(query_params): web::Query<GetPlanetQueryParams>,
     planet_service: web::Data<PlanetService>,
 ) -> Result<HttpResponse, CustomError> {
-    let planet = planet_service.get_planet(&planet_id.into_inner()).await?;
-    Ok(HttpResponse::Ok().json(PlanetDto::from(planet)))
+    assert_can_include_deleted(&req, query_params.include_deleted)?;
+
+    let planet_id = planet_id.into_inner();
+    let planet = if query_params.include_deleted {
+        planet_service
+            .get_planet_including_deleted(&planet_id)
+            .await?
+    } else {
+        planet_service.get_planet(&planet_id).await?
+    };
+    negotiation::planet_response(&req, StatusCode::OK, planet)
 }
 
+#[utoipa::path(
+    put,
+    path = "/v1/planets/{planet_id}",
+    tag = "planets",
+    params(
+        ("planet_id" = String, Path),
+        ("X-Actor" = Option<String>, Header,
+            description = "Actor recorded in the revision history; it isn't verified"
+        ),
+    ),
+    request_body(content(
+        (PlanetDto = "application/json"),
+        (PlanetDto = "application/cbor"),
+        (PlanetDto = "application/msgpack"),
+        (String = "text/csv"),
+    )),
+    responses(
+        (status = 200, description = "Updated planet", body = PlanetDto),
+        (status = 400, description = "Invalid planet", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 404, description = "Planet isn't found", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 409, description = "A planet with the same name already exists", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 415, description = "Unsupported format of the planet", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 500, description = "MongoDB, Redis or internal error", body = ErrorResponse, content_type = "application/problem+json"),
+    )
+)]
 pub async fn update_planet(
+    req: HttpRequest,
     planet_id: web::Path<String>,
-    planet_dto: web::Json<PlanetDto>,
+    planet: PlanetPayload,
     planet_service: web::Data<PlanetService>,
 ) -> Result<HttpResponse, CustomError> {
     let planet = planet_service
-        .update_planet(&planet_id.into_inner(), planet_dto.into_inner().into())
+        .update_planet(
+            &planet_id.into_inner(),
+            planet.into_inner(),
+            &get_actor(&req),
+        )
         .await?;
 
-    Ok(HttpResponse::Ok().json(PlanetDto::from(planet)))
+    negotiation::planet_response(&req, StatusCode::OK, planet)
 }
 
+#[utoipa::path(
+    delete,
+    path = "/v1/planets/{planet_id}",
+    tag = "planets",
+    params(
+        ("planet_id" = String, Path),
+        ("X-Actor" = Option<String>, Header,
+            description = "Actor recorded in the revision history; it isn't verified"
+        ),
+    ),
+    responses(
+        (status = 200, description = "Planet is deleted"),
+        (status = 404, description = "Planet isn't found", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 500, description = "MongoDB, Redis or internal error", body = ErrorResponse, content_type = "application/problem+json"),
+    )
+)]
 pub async fn delete_planet(
+    req: HttpRequest,
     planet_id: web::Path<String>,
     planet_service: web::Data<PlanetService>,
 ) -> Result<HttpResponse, CustomError> {
     planet_service
-        .delete_planet(&planet_id.into_inner())
+        .delete_planet(&planet_id.into_inner(), &get_actor(&req))
         .await?;
 
     Ok(HttpResponse::Ok().finish())
 }
 
+pub async fn restore_planet(
+    req: HttpRequest,
+    planet_id: web::Path<String>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let planet = planet_service
+        .restore_planet(&planet_id.into_inner(), &get_actor(&req))
+        .await?;
+
+    negotiation::planet_response(&req, StatusCode::OK, planet)
+}
+
+pub async fn get_planet_history(
+    planet_id: web::Path<String>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let revisions = planet_service
+        .get_planet_history(&planet_id.into_inner())
+        .await?;
+
+    Ok(HttpResponse::Ok().json(
+        revisions
+            .into_iter()
+            .map(RevisionDto::from)
+            .collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn revert_planet(
+    req: HttpRequest,
+    path: web::Path<(String, i64)>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let (planet_id, revision) = path.into_inner();
+    let planet = planet_service
+        .revert_planet(&planet_id, revision, &get_actor(&req))
+        .await?;
+
+    negotiation::planet_response(&req, StatusCode::OK, planet)
+}
+
+pub async fn get_planets_v2(
+    req: HttpRequest,
+    web::Query(query_params): web::Query<GetPlanetsQueryParams>,
+    rate_limit_service: web::Data<RateLimitingService>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    rate_limit_service
+        .assert_rate_limit_not_exceeded(get_ip_addr(&req)?)
+        .await?;
+    assert_can_include_deleted(&req, query_params.include_deleted)?;
+
+    let units = negotiation::get_units(&req)?;
+    let planets = planet_service
+        .get_planets(query_params.r#type, query_params.include_deleted)
+        .await?
+        .into_iter()
+        .map(|planet| PlanetV2Dto::new(planet, units))
+        .collect();
+
+    Ok(HttpResponse::Ok().json(EnvelopeDto::list(planets)))
+}
+
+pub async fn create_planet_v2(
+    req: HttpRequest,
+    planet: PlanetPayload,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let planet = planet_service
+        .create_planet(planet.into_inner(), &get_actor(&req))
+        .await?;
+
+    let planet = PlanetV2Dto::new(planet, negotiation::get_units(&req)?);
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, planet.links.self_link.clone()))
+        .json(EnvelopeDto::new(planet)))
+}
+
+pub async fn get_planet_v2(
+    req: HttpRequest,
+    planet_id: web::Path<String>,
+    web::Query(query_params): web::Query<GetPlanetQueryParams>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    assert_can_include_deleted(&req, query_params.include_deleted)?;
+
+    let planet_id = planet_id.into_inner();
+    let planet = if query_params.include_deleted {
+        planet_service
+            .get_planet_including_deleted(&planet_id)
+            .await?
+    } else {
+        planet_service.get_planet(&planet_id).await?
+    };
+
+    let planet = PlanetV2Dto::new(planet, negotiation::get_units(&req)?);
+    Ok(HttpResponse::Ok().json(EnvelopeDto::new(planet)))
+}
+
+pub async fn update_planet_v2(
+    req: HttpRequest,
+    planet_id: web::Path<String>,
+    planet: PlanetPayload,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let planet = planet_service
+        .update_planet(
+            &planet_id.into_inner(),
+            planet.into_inner(),
+            &get_actor(&req),
+        )
+        .await?;
+
+    let planet = PlanetV2Dto::new(planet, negotiation::get_units(&req)?);
+    Ok(HttpResponse::Ok().json(EnvelopeDto::new(planet)))
+}
+
+pub async fn get_planet_history_v2(
+    planet_id: web::Path<String>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let revisions = planet_service
+        .get_planet_history(&planet_id.into_inner())
+        .await?;
+
+    Ok(HttpResponse::Ok().json(EnvelopeDto::list(
+        revisions.into_iter().map(RevisionDto::from).collect(),
+    )))
+}
+
+#[utoipa::path(
+    get,
+    path = "/v1/planets/{planet_id}/image",
+    tag = "planets",
+    params(("planet_id" = String, Path)),
+    responses(
+        (status = 200, description = "Image of a planet", content(("image/png"))),
+        (status = 404, description = "Planet isn't found", body = ErrorResponse, content_type = "application/problem+json"),
+        (status = 500, description = "MongoDB, Redis or internal error", body = ErrorResponse, content_type = "application/problem+json"),
+    )
+)]
 pub async fn get_image_of_planet(
     planet_id: web::Path<String>,
     planet_service: web::Data<PlanetService>,
@@ -86,12 +579,22 @@ pub async fn get_image_of_planet(
         .body(image))
 }
 
-pub async fn sse(broadcaster: web::Data<Mutex<Broadcaster>>) -> Result<HttpResponse, CustomError> {
+#[utoipa::path(
+    get,
+    path = "/events",
+    tag = "events",
+    responses(
+        (status = 200, description = "Server-sent events about created planets", body = String, content_type = "text/event-stream"),
+    )
+)]
+pub async fn sse(
+    req: HttpRequest,
+    broadcaster: web::Data<Mutex<Broadcaster>>,
+) -> Result<HttpResponse, CustomError> {
     let rx = broadcaster
         .lock()
         .expect("Can't lock broadcaster")
-        .new_client()
-        .await;
+        .new_client(get_ip_addr(&req).ok());
     let response_stream = tokio_stream::wrappers::ReceiverStream::new(rx);
 
     Ok(HttpResponse::build(StatusCode::OK)
@@ -99,6 +602,70 @@ pub async fn sse(broadcaster: web::Data<Mutex<Broadcaster>>) -> Result<HttpRespo
         .streaming(response_stream))
 }
 
+pub async fn graphql(
+    req: HttpRequest,
+    web::Json(request): web::Json<async_graphql::Request>,
+    schema: web::Data<PlanetSchema>,
+    rate_limit_service: web::Data<RateLimitingService>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    rate_limit_service
+        .assert_rate_limit_not_exceeded(get_ip_addr(&req)?)
+        .await?;
+    if negotiation::get_units(&req)?.is_some() {
+        return Err(CustomError::BadRequest {
+            message: String::from("Units are specified by `unit` argument of `meanRadius` field"),
+        });
+    }
+
+    let request = request
+        .data(graphql::create_planet_loader(planet_service))
+        .data(Actor(get_actor(&req)))
+        .data(AdminAccess(admin::authorize_admin_api(&req).is_ok()));
+
+    // subscriptions are served over SSE in "distinct connections" mode of GraphQL over SSE protocol
+    if accepts(&req, mime::TEXT_EVENT_STREAM.essence_str()) {
+        let events = schema
+            .execute_stream(request)
+            .map(|response| -> Result<Bytes, CustomError> {
+                let data = serde_json::to_string(&response)?;
+                Ok(Bytes::from(format!("event: next\ndata: {}\n\n", data)))
+            })
+            .chain(tokio_stream::once(Ok(Bytes::from_static(
+                b"event: complete\ndata:\n\n",
+            ))));
+
+        return Ok(HttpResponse::Ok()
+            .insert_header(header::ContentType(mime::TEXT_EVENT_STREAM))
+            .streaming(events));
+    }
+
+    let response = schema.execute(request).await;
+    Ok(HttpResponse::Ok().json(response))
+}
+
+pub async fn graphiql() -> Result<HttpResponse, CustomError> {
+    let content = GraphiQLSource::build().endpoint("/graphql").finish();
+
+    Ok(HttpResponse::Ok()
+        .insert_header(header::ContentType(mime::TEXT_HTML))
+        .body(content))
+}
+
+pub async fn live() -> Result<HttpResponse, CustomError> {
+    Ok(HttpResponse::Ok().json(HealthDto::live()))
+}
+
+pub async fn ready(health_service: web::Data<HealthService>) -> Result<HttpResponse, CustomError> {
+    let health = HealthDto::from(health_service.check_readiness().await);
+
+    let status = match health.status {
+        HealthStatus::Up => StatusCode::OK,
+        HealthStatus::Down => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    Ok(HttpResponse::build(status).json(health))
+}
+
 pub async fn index() -> Result<HttpResponse, CustomError> {
     let content = include_str!("index.html");
 
@@ -107,19 +674,183 @@ pub async fn index() -> Result<HttpResponse, CustomError> {
         .body(content))
 }
 
-pub async fn metrics() -> Result<HttpResponse, CustomError> {
-    let encoder = TextEncoder::new();
+pub async fn openapi() -> Result<HttpResponse, CustomError> {
+    let content = ApiDoc::openapi().to_json()?;
+
+    Ok(HttpResponse::Ok()
+        .insert_header(header::ContentType(mime::APPLICATION_JSON))
+        .body(content))
+}
+
+pub async fn openapi_ui() -> Result<HttpResponse, CustomError> {
+    let content = include_str!("openapi.html");
+
+    Ok(HttpResponse::Ok()
+        .insert_header(header::ContentType(mime::TEXT_HTML))
+        .body(content))
+}
+
+/// Metrics are encoded in OpenMetrics format if it's preferred by a client (e.g. Prometheus),
+/// otherwise in Prometheus text format
+pub async fn metrics(_: AdminAuth, req: HttpRequest) -> Result<HttpResponse, CustomError> {
+    let metric_families = prometheus::gather();
+
+    if accepts_openmetrics(&req) {
+        return Ok(HttpResponse::Ok()
+            .content_type(openmetrics::CONTENT_TYPE)
+            .body(openmetrics::encode(&metric_families)));
+    }
+
     let mut buffer = vec![];
-    encoder
-        .encode(&prometheus::gather(), &mut buffer)
+    TextEncoder::new()
+        .encode(&metric_families, &mut buffer)
         .expect("Failed to encode metrics");
 
-    let response = String::from_utf8(buffer.clone()).expect("Failed to convert bytes to string");
-    buffer.clear();
-
     Ok(HttpResponse::Ok()
         .insert_header(header::ContentType(mime::TEXT_PLAIN))
-        .body(response))
+        .body(buffer))
+}
+
+pub async fn flush_cache(
+    _: AdminApiAuth,
+    query_params: web::Query<FlushCacheQueryParams>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    let deleted = planet_service
+        .flush_cache(query_params.prefix.as_deref())
+        .await?;
+
+    Ok(HttpResponse::Ok().json(FlushCacheDto { deleted }))
+}
+
+pub async fn invalidate_planet_cache(
+    _: AdminApiAuth,
+    planet_id: web::Path<String>,
+    planet_service: web::Data<PlanetService>,
+) -> Result<HttpResponse, CustomError> {
+    planet_service
+        .invalidate_planet_cache(&planet_id.into_inner())
+        .await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+/// A client is identified by its IP address like in rate limiting
+pub async fn get_rate_limit(
+    _: AdminApiAuth,
+    client: web::Path<String>,
+    rate_limit_service: web::Data<RateLimitingService>,
+) -> Result<HttpResponse, CustomError> {
+    let client = parse_client(&client)?;
+    let requests = rate_limit_service.get_request_count(&client).await?;
+
+    Ok(HttpResponse::Ok().json(RateLimitDto {
+        client,
+        requests,
+        limit: rate_limit_service.max_requests_per_minute(),
+    }))
+}
+
+pub async fn reset_rate_limit(
+    _: AdminApiAuth,
+    client: web::Path<String>,
+    rate_limit_service: web::Data<RateLimitingService>,
+) -> Result<HttpResponse, CustomError> {
+    rate_limit_service.reset(&parse_client(&client)?).await?;
+
+    Ok(HttpResponse::Ok().finish())
+}
+
+pub async fn get_sse_clients(
+    _: AdminApiAuth,
+    broadcaster: web::Data<Mutex<Broadcaster>>,
+) -> Result<HttpResponse, CustomError> {
+    let broadcaster = broadcaster.lock().expect("Can't lock broadcaster");
+    let clients = SseClientsDto {
+        clients: broadcaster
+            .clients()
+            .into_iter()
+            .map(SseClientDto::from)
+            .collect(),
+        graphql_subscribers: broadcaster.subscriber_count(),
+    };
+
+    Ok(HttpResponse::Ok().json(clients))
+}
+
+// the address is normalized the same way as in `get_ip_addr`, e.g. for IPv6
+fn parse_client(client: &str) -> Result<String, CustomError> {
+    client
+        .parse::<IpAddr>()
+        .map(|ip_addr| ip_addr.to_string())
+        .map_err(|_| CustomError::BadRequest {
+            message: format!("Client {} is not a valid IP address", client),
+        })
+}
+
+fn accepts_openmetrics(req: &HttpRequest) -> bool {
+    match header::Accept::parse(req) {
+        Ok(accept) => accept
+            .ranked()
+            .first()
+            .is_some_and(|mime| mime.essence_str() == OPENMETRICS_MIME_TYPE),
+        Err(_) => false,
+    }
+}
+
+fn to_ndjson_stream(
+    planets: PlanetStream,
+    units: Option<Units>,
+) -> impl Stream<Item = Result<Bytes, CustomError>> {
+    planets.map(move |planet| {
+        let mut line = serde_json::to_vec(&PlanetDto::with_units(planet?, units))?;
+        line.push(b'\n');
+        Ok(Bytes::from(line))
+    })
+}
+
+/// Planets are serialized one by one as they are read from the stream, so the whole list is never kept in memory
+fn to_json_array_stream(
+    planets: PlanetStream,
+    units: Option<Units>,
+) -> impl Stream<Item = Result<Bytes, CustomError>> {
+    let mut is_first = true;
+    let elements = planets.map(move |planet| {
+        let separator: &[u8] = if is_first { b"" } else { b"," };
+        is_first = false;
+        let element = serde_json::to_vec(&PlanetDto::with_units(planet?, units))?;
+        Ok(Bytes::from([separator, &element].concat()))
+    });
+
+    tokio_stream::once(Ok(Bytes::from_static(b"[")))
+        .chain(elements)
+        .chain(tokio_stream::once(Ok(Bytes::from_static(b"]"))))
+}
+
+fn accepts(req: &HttpRequest, content_type: &str) -> bool {
+    req.headers()
+        .get(header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains(content_type))
+}
+
+// deleted planets are visible to admins only
+fn assert_can_include_deleted(req: &HttpRequest, include_deleted: bool) -> Result<(), CustomError> {
+    if include_deleted {
+        admin::authorize_admin_api(req)
+    } else {
+        Ok(())
+    }
+}
+
+// there is no authentication yet, so an actor is identified by a header that isn't verified: any
+// client can put any name there, so the actor of a revision is informational only
+fn get_actor(req: &HttpRequest) -> String {
+    req.headers()
+        .get(ACTOR_HEADER_NAME)
+        .and_then(|value| value.to_str().ok())
+        .map(String::from)
+        .unwrap_or_else(|| String::from(ANONYMOUS_ACTOR))
 }
 
 fn get_ip_addr(req: &HttpRequest) -> Result<String, CustomError> {
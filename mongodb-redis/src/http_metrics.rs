@@ -0,0 +1,125 @@
+use std::future::{ready, Ready};
+use std::time::Instant;
+
+use actix_web::body::{BodySize, MessageBody};
+use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::Error;
+use futures::future::LocalBoxFuture;
+
+use crate::{metrics, telemetry};
+
+/// Records HTTP metrics labeled by method, route pattern (e.g. `/v1/planets/{planet_id}`) and status
+pub struct HttpMetrics;
+
+impl<S, B> Transform<S, ServiceRequest> for HttpMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = HttpMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(HttpMetricsMiddleware { service }))
+    }
+}
+
+pub struct HttpMetricsMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for HttpMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        // requests to unregistered paths aren't recorded; otherwise flooding the application with
+        // requests to different paths would cause high memory consumption of the application and
+        // Prometheus server and could also overflow Prometheus's TSDB
+        let path = match req.match_pattern() {
+            Some(path) => path,
+            None => return Box::pin(self.service.call(req)),
+        };
+        let method = req.method().to_string();
+
+        let in_flight = InFlightRequest::start();
+        let started_at = Instant::now();
+        let fut = self.service.call(req);
+
+        Box::pin(async move {
+            let result = fut.await;
+            drop(in_flight);
+
+            let status = match &result {
+                Ok(res) => res.status(),
+                Err(e) => e.as_response_error().status_code(),
+            };
+            let status = status.as_str();
+
+            metrics::HTTP_REQUESTS_TOTAL
+                .with_label_values(&[&method, &path, api_version(&path), status])
+                .inc();
+            // the request span is entered here, so its trace is linked to the observation
+            metrics::observe_http_response_time(
+                &method,
+                &path,
+                status,
+                started_at.elapsed().as_secs_f64(),
+                telemetry::current_trace_id(),
+            );
+
+            if let Ok(res) = &result {
+                let size = match res.response().body().size() {
+                    BodySize::None => Some(0),
+                    BodySize::Sized(size) => Some(size),
+                    BodySize::Stream => None,
+                };
+                if let Some(size) = size {
+                    metrics::HTTP_RESPONSE_SIZE_BYTES
+                        .with_label_values(&[&method, &path])
+                        .observe(size as f64);
+                }
+            }
+
+            result
+        })
+    }
+}
+
+// the gauge is decremented even if the request is cancelled, e.g. when a client disconnects
+struct InFlightRequest;
+
+impl InFlightRequest {
+    fn start() -> Self {
+        metrics::HTTP_REQUESTS_IN_FLIGHT.inc();
+        InFlightRequest
+    }
+}
+
+impl Drop for InFlightRequest {
+    fn drop(&mut self) {
+        metrics::HTTP_REQUESTS_IN_FLIGHT.dec();
+    }
+}
+
+fn api_version(path: &str) -> &'static str {
+    if path.starts_with("/v1/") {
+        "v1"
+    } else if path.starts_with("/v2/") {
+        "v2"
+    } else if path.starts_with("/planets") {
+        "unversioned"
+    } else {
+        ""
+    }
+}
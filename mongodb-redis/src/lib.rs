@@ -0,0 +1,25 @@
+pub mod admin;
+pub mod broadcaster;
+pub mod change_stream;
+pub mod config;
+pub mod db;
+pub mod dto;
+pub mod errors;
+pub mod graphql;
+pub mod handlers;
+pub mod http_metrics;
+pub mod memory;
+pub mod metrics;
+pub mod model;
+pub mod negotiation;
+pub mod openapi;
+pub mod openmetrics;
+pub mod outbox;
+pub mod purge;
+pub mod redis;
+pub mod request_id;
+pub mod routes;
+pub mod services;
+pub mod shutdown;
+pub mod storage;
+pub mod telemetry;
@@ -1,37 +1,56 @@
-use std::env;
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix_web::dev::Service;
 use actix_web::web::Data;
-use actix_web::{web, App, HttpServer};
-use log::info;
-
-use crate::broadcaster::Broadcaster;
-use crate::db::MongoDbClient;
-use crate::services::{PlanetService, RateLimitingService};
-use prometheus::HistogramTimer;
-
-mod broadcaster;
-mod db;
-mod dto;
-mod errors;
-mod handlers;
-mod metrics;
-mod model;
-mod redis;
-mod services;
+use actix_web::{App, HttpServer};
+use tracing::{error, info, Instrument};
+
+use mongodb_redis::broadcaster::Broadcaster;
+use mongodb_redis::config::{AppConfig, TracingConfig};
+use mongodb_redis::db::MongoDbClient;
+use mongodb_redis::http_metrics::HttpMetrics;
+use mongodb_redis::request_id::RequestId;
+use mongodb_redis::services::{EventPublishing, HealthService, PlanetService, RateLimitingService};
+use mongodb_redis::{change_stream, graphql, outbox, purge, redis, routes, shutdown, telemetry};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv::from_filename(".env.local").ok();
-    env_logger::init();
-
-    info!("Starting MongoDB & Redis demo server");
 
-    let mongodb_uri = env::var("MONGODB_URI").expect("MONGODB_URI env var should be specified");
-    let mongodb_client = MongoDbClient::new(mongodb_uri).await;
+    let config = AppConfig::load().unwrap_or_else(|e| {
+        telemetry::init(&TracingConfig::default());
+        error!("{}", e);
+        process::exit(1)
+    });
+    let tracer_provider = telemetry::init(&config.tracing);
 
-    let redis_uri = env::var("REDIS_URI").expect("REDIS_URI env var should be specified");
-    let redis_client = redis::create_client(redis_uri)
+    info!("Starting MongoDB & Redis demo server");
+    info!(
+        "Effective configuration:\n{}",
+        toml::to_string(&config.redacted()).expect("Can't serialize configuration")
+    );
+
+    let mongodb_client = MongoDbClient::new(&config.mongodb).await;
+    // writes to the outbox must be atomic, and change streams are available only where
+    // transactions are
+    if (config.events.enable_outbox || config.events.enable_change_stream)
+        && !mongodb_client.supports_transactions()
+    {
+        error!(
+            "events.enable_outbox and events.enable_change_stream require MongoDB replica set or sharded cluster"
+        );
+        process::exit(1);
+    }
+    if let Err(e) = mongodb_client.ensure_indexes().await {
+        error!("Can't create MongoDB indexes: {}", e);
+        process::exit(1);
+    }
+
+    let event_publishing = EventPublishing::from(&config.events);
+
+    let redis_client = redis::create_client(config.redis.uri.clone())
         .await
         .expect("Can't create Redis client");
     let redis_connection_manager = redis_client
@@ -41,83 +60,159 @@ async fn main() -> std::io::Result<()> {
 
     let broadcaster = Broadcaster::create();
 
-    redis::start_pubsub(&redis_client, broadcaster.clone())
+    let pubsub_listener = redis::start_pubsub(&redis_client, broadcaster.clone())
         .await
         .expect("Can't start Redis Pub/Sub");
 
     let planet_service = Data::new(PlanetService::new(
-        mongodb_client,
-        redis_client,
-        redis_connection_manager.clone(),
+        Arc::new(mongodb_client.clone()),
+        Arc::new(redis_connection_manager.clone()),
+        Arc::new(redis_connection_manager.clone()),
+        event_publishing,
     ));
 
-    let rate_limiting_service = Data::new(RateLimitingService::new(redis_connection_manager));
+    let pubsub_listener = Arc::new(pubsub_listener);
+    let mut background_tasks = Vec::new();
+
+    if config.events.enable_change_stream {
+        background_tasks.push(change_stream::start_change_stream(
+            mongodb_client.clone(),
+            planet_service.clone(),
+            broadcaster.clone(),
+            config.events.change_stream_consumer.clone(),
+        ));
+    }
+
+    if event_publishing == EventPublishing::Outbox {
+        background_tasks.push(outbox::start_outbox_relay(
+            mongodb_client.clone(),
+            redis_connection_manager.clone(),
+        ));
+    }
+
+    background_tasks.push(purge::start_purge(
+        mongodb_client.clone(),
+        chrono::Duration::days(config.purge.deleted_planets_retention_days),
+    ));
 
-    let enable_writing_handlers = env::var("ENABLE_WRITING_HANDLERS")
-        .expect("ENABLE_WRITING_HANDLERS env var should be specified")
-        .parse::<bool>()
-        .expect("Can't parse ENABLE_WRITING_HANDLERS");
+    let health_service = Data::new(HealthService::new(
+        Arc::new(mongodb_client.clone()),
+        Arc::new(redis_connection_manager.clone()),
+        pubsub_listener.clone(),
+    ));
 
-    HttpServer::new(move || {
-        let mut app = App::new()
-            .wrap_fn(|req, srv| {
-                let mut histogram_timer: Option<HistogramTimer> = None;
-                let request_path = req.path();
-                let is_registered_resource = req.resource_map().has_resource(request_path);
-                // this check prevents possible DoS attacks that can be done by flooding the application
-                // using requests to different unregistered paths. That can cause high memory consumption
-                // of the application and Prometheus server and also overflow Prometheus's TSDB
-                if is_registered_resource {
-                    let request_method = req.method().to_string();
-                    histogram_timer = Some(
-                        metrics::HTTP_RESPONSE_TIME_SECONDS
-                            .with_label_values(&[&request_method, request_path])
-                            .start_timer(),
-                    );
-                    metrics::HTTP_REQUESTS_TOTAL
-                        .with_label_values(&[&request_method, request_path])
-                        .inc();
-                }
+    let shutdown_redis_connection_manager = redis_connection_manager.clone();
 
-                let fut = srv.call(req);
+    let rate_limiting_service = Data::new(RateLimitingService::new(
+        Arc::new(redis_connection_manager),
+        config.rate_limit.max_requests_per_minute,
+    ));
+
+    let enable_writing_handlers = config.server.enable_writing_handlers;
+
+    let graphql_schema = Data::new(graphql::create_schema(
+        planet_service.clone(),
+        broadcaster.clone(),
+        enable_writing_handlers,
+    ));
 
-                async {
+    let shutdown_broadcaster = broadcaster.clone();
+
+    let admin_config = Data::new(config.admin.clone());
+    let separate_admin_server = !config.admin.bind_address.is_empty();
+
+    let admin_server = if separate_admin_server {
+        let admin_config = admin_config.clone();
+        let planet_service = planet_service.clone();
+        let rate_limiting_service = rate_limiting_service.clone();
+        let broadcaster = broadcaster.clone();
+        let admin_server = HttpServer::new(move || {
+            App::new()
+                .wrap(RequestId)
+                .configure(routes::configure_admin)
+                .app_data(admin_config.clone())
+                .app_data(planet_service.clone())
+                .app_data(rate_limiting_service.clone())
+                .app_data(broadcaster.clone())
+        })
+        .disable_signals()
+        .workers(1)
+        .bind(&config.admin.bind_address)?
+        .run();
+        info!(
+            "Admin endpoints are served on {}",
+            config.admin.bind_address
+        );
+        Some(admin_server)
+    } else {
+        None
+    };
+    let admin_server = admin_server
+        .map(|admin_server| (admin_server.handle(), actix_web::rt::spawn(admin_server)));
+
+    let server = HttpServer::new(move || {
+        App::new()
+            .wrap(HttpMetrics)
+            .wrap_fn(|req, srv| {
+                let span = telemetry::request_span(&req);
+                let fut = span.in_scope(|| srv.call(req));
+
+                let request_span = span.clone();
+                async move {
                     let res = fut.await?;
-                    if let Some(histogram_timer) = histogram_timer {
-                        histogram_timer.observe_duration();
-                    };
+                    telemetry::record_response(&request_span, &res);
                     Ok(res)
                 }
+                .instrument(span)
+            })
+            .wrap(RequestId)
+            .configure(|cfg| {
+                routes::configure(cfg, enable_writing_handlers, !separate_admin_server)
             })
-            .route("/planets", web::get().to(handlers::get_planets))
-            .route("/planets/{planet_id}", web::get().to(handlers::get_planet))
-            .route(
-                "/planets/{planet_id}/image",
-                web::get().to(handlers::get_image_of_planet),
-            )
-            .route("/events", web::get().to(handlers::sse))
-            .route("/", web::get().to(handlers::index))
-            .route("/metrics", web::get().to(handlers::metrics))
+            .app_data(admin_config.clone())
             .app_data(planet_service.clone())
             .app_data(rate_limiting_service.clone())
-            .app_data(broadcaster.clone());
-
-        if enable_writing_handlers {
-            app = app
-                .route("/planets", web::post().to(handlers::create_planet))
-                .route(
-                    "/planets/{planet_id}",
-                    web::put().to(handlers::update_planet),
-                )
-                .route(
-                    "/planets/{planet_id}",
-                    web::delete().to(handlers::delete_planet),
-                );
-        }
-
-        app
+            .app_data(health_service.clone())
+            .app_data(broadcaster.clone())
+            .app_data(graphql_schema.clone())
     })
-    .bind("0.0.0.0:9000")?
-    .run()
-    .await
+    // signals are handled by the application to notify SSE clients before the server is stopped
+    .disable_signals()
+    .shutdown_timeout(
+        shutdown::server_shutdown_timeout(Duration::from_secs(
+            config.server.shutdown_timeout_seconds,
+        ))
+        .as_secs(),
+    )
+    .bind(&config.server.bind_address)?
+    .run();
+
+    let server_handle = server.handle();
+    let mut server_task = actix_web::rt::spawn(server);
+
+    tokio::select! {
+        result = &mut server_task => return result.expect("HTTP server task failed"),
+        _ = shutdown::wait_for_signal() => {}
+    }
+
+    let result = shutdown::shutdown(
+        shutdown::Servers {
+            server: server_handle,
+            server_task,
+            admin_server,
+        },
+        shutdown_broadcaster,
+        background_tasks,
+        shutdown::Dependencies {
+            mongodb_client,
+            redis_connection_manager: shutdown_redis_connection_manager,
+            pubsub_listener,
+        },
+        event_publishing == EventPublishing::Outbox,
+        Duration::from_secs(config.server.shutdown_timeout_seconds),
+    )
+    .await;
+
+    telemetry::shutdown(tracer_provider);
+    result
 }
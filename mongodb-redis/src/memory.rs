@@ -0,0 +1,606 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+use actix_web::web::Data;
+use async_trait::async_trait;
+use mongodb::bson::{oid::ObjectId, Document};
+
+use crate::broadcaster::Broadcaster;
+use crate::errors::CustomError::{Conflict, MongoDbError, NotFound};
+use crate::errors::{CustomError, DUPLICATE_NAME_MESSAGE};
+use crate::model::{
+    OutboxEvent, Planet, PlanetStats, PlanetType, PlanetTypeCount, Revision, RevisionOperation,
+};
+use crate::storage::{Cache, EventPublisher, PlanetRepository, PlanetStream, RateLimiterStore};
+
+/// Behaves like `MongoDbClient` without a database; is used in tests
+#[derive(Default)]
+pub struct InMemoryPlanetRepository {
+    state: Mutex<RepositoryState>,
+}
+
+#[derive(Default)]
+struct RepositoryState {
+    // ordered by id like MongoDB's `_id` index
+    planets: BTreeMap<ObjectId, Planet>,
+    revisions: Vec<Revision>,
+    outbox: Vec<OutboxEvent>,
+}
+
+impl InMemoryPlanetRepository {
+    pub fn new() -> Self {
+        InMemoryPlanetRepository::default()
+    }
+
+    /// Payloads of events that haven't been relayed yet
+    pub fn outbox_payloads(&self) -> Vec<String> {
+        self.lock()
+            .outbox
+            .iter()
+            .map(|event| event.payload.clone())
+            .collect()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, RepositoryState> {
+        self.state.lock().expect("Can't lock repository state")
+    }
+
+    fn find_planets(&self, planet_type: Option<PlanetType>, include_deleted: bool) -> Vec<Planet> {
+        self.lock()
+            .planets
+            .values()
+            .filter(|planet| include_deleted || planet.deleted_at.is_none())
+            .filter(|planet| planet_type.is_none() || planet_type == Some(planet.r#type))
+            .cloned()
+            .collect()
+    }
+}
+
+impl RepositoryState {
+    fn insert_planet(&mut self, mut planet: Planet, actor: &str) -> Result<Planet, CustomError> {
+        let id = *planet.id.get_or_insert_with(ObjectId::new);
+        if self.planets.contains_key(&id) {
+            return Err(MongoDbError {
+                message: format!("Duplicate key: {}", id),
+            });
+        }
+        self.assert_name_is_unique(&planet)?;
+
+        self.planets.insert(id, planet.clone());
+        self.record_revision(RevisionOperation::Create, actor, None, Some(&planet));
+
+        Ok(planet)
+    }
+
+    /// Behaves like the unique index of names of planets that aren't deleted
+    fn assert_name_is_unique(&self, planet: &Planet) -> Result<(), CustomError> {
+        let is_duplicate = planet.deleted_at.is_none()
+            && self.planets.values().any(|other| {
+                other.id != planet.id && other.name == planet.name && other.deleted_at.is_none()
+            });
+        if is_duplicate {
+            return Err(Conflict {
+                message: String::from(DUPLICATE_NAME_MESSAGE),
+            });
+        }
+
+        Ok(())
+    }
+
+    fn record_revision(
+        &mut self,
+        operation: RevisionOperation,
+        actor: &str,
+        before: Option<&Planet>,
+        after: Option<&Planet>,
+    ) {
+        let planet_id = before
+            .or(after)
+            .and_then(|planet| planet.id)
+            .expect("Planet.id is not specified");
+        let before = before.map(Document::from);
+        let after = after.map(Document::from);
+
+        let last_revision = self
+            .revisions
+            .iter()
+            .filter(|revision| revision.planet_id == planet_id)
+            .map(|revision| revision.revision)
+            .max();
+
+        self.revisions.push(Revision {
+            id: Some(ObjectId::new()),
+            planet_id,
+            revision: last_revision.map_or(1, |last_revision| last_revision + 1),
+            operation,
+            actor: actor.to_string(),
+            timestamp: mongodb::bson::DateTime::now(),
+            diff: crate::db::diff(before.as_ref(), after.as_ref()),
+            before,
+            after,
+        });
+    }
+}
+
+#[async_trait]
+impl PlanetRepository for InMemoryPlanetRepository {
+    async fn get_planets(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+    ) -> Result<Vec<Planet>, CustomError> {
+        Ok(self.find_planets(planet_type, include_deleted))
+    }
+
+    async fn get_planets_stream(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+    ) -> Result<PlanetStream, CustomError> {
+        let planets = self.find_planets(planet_type, include_deleted);
+        Ok(Box::pin(futures::stream::iter(
+            planets.into_iter().map(Ok::<_, CustomError>),
+        )))
+    }
+
+    async fn get_planets_page(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+        skip: u64,
+        limit: i64,
+    ) -> Result<Vec<Planet>, CustomError> {
+        Ok(self
+            .find_planets(planet_type, include_deleted)
+            .into_iter()
+            .skip(skip as usize)
+            .take(limit.max(0) as usize)
+            .collect())
+    }
+
+    async fn get_planets_by_ids(&self, ids: Vec<ObjectId>) -> Result<Vec<Planet>, CustomError> {
+        let state = self.lock();
+        Ok(ids
+            .iter()
+            .filter_map(|id| state.planets.get(id))
+            .filter(|planet| planet.deleted_at.is_none())
+            .cloned()
+            .collect())
+    }
+
+    async fn get_planet_stats(&self) -> Result<PlanetStats, CustomError> {
+        let planets = self.find_planets(None, false);
+
+        // types are sorted by name like in the aggregation pipeline
+        let mut count_by_type: BTreeMap<String, PlanetTypeCount> = BTreeMap::new();
+        for planet in planets.iter() {
+            count_by_type
+                .entry(planet.r#type.to_string())
+                .or_insert(PlanetTypeCount {
+                    r#type: planet.r#type,
+                    count: 0,
+                })
+                .count += 1;
+        }
+
+        let mean_radiuses: Vec<f64> = planets
+            .iter()
+            .map(|planet| planet.mean_radius as f64)
+            .collect();
+        let landing_dates: Vec<mongodb::bson::DateTime> = planets
+            .iter()
+            .flat_map(|planet| planet.satellites.iter().flatten())
+            .filter_map(|satellite| satellite.first_spacecraft_landing_date)
+            .collect();
+
+        Ok(PlanetStats {
+            count_by_type: count_by_type.into_values().collect(),
+            min_mean_radius: mean_radiuses.iter().copied().reduce(f64::min),
+            max_mean_radius: mean_radiuses.iter().copied().reduce(f64::max),
+            avg_mean_radius: if mean_radiuses.is_empty() {
+                None
+            } else {
+                Some(mean_radiuses.iter().sum::<f64>() / mean_radiuses.len() as f64)
+            },
+            total_satellites: planets
+                .iter()
+                .map(|planet| planet.satellites.as_ref().map_or(0, Vec::len) as i64)
+                .sum(),
+            earliest_landing_date: landing_dates.iter().min().copied(),
+            latest_landing_date: landing_dates.iter().max().copied(),
+        })
+    }
+
+    async fn find_planet_by_name(&self, name: &str) -> Result<Option<Planet>, CustomError> {
+        Ok(self
+            .find_planets(None, false)
+            .into_iter()
+            .find(|planet| planet.name == name))
+    }
+
+    async fn get_planet(&self, id: ObjectId, include_deleted: bool) -> Result<Planet, CustomError> {
+        self.lock()
+            .planets
+            .get(&id)
+            .filter(|planet| include_deleted || planet.deleted_at.is_none())
+            .cloned()
+            .ok_or(NotFound {
+                message: format!("Can't find a planet by id: {}", &id),
+            })
+    }
+
+    async fn create_planet(&self, planet: Planet, actor: &str) -> Result<Planet, CustomError> {
+        self.lock().insert_planet(planet, actor)
+    }
+
+    async fn create_planet_with_event(
+        &self,
+        planet: Planet,
+        event: OutboxEvent,
+        actor: &str,
+    ) -> Result<Planet, CustomError> {
+        let mut state = self.lock();
+        let created_planet = state.insert_planet(planet, actor)?;
+        state.outbox.push(event);
+
+        Ok(created_planet)
+    }
+
+    async fn update_planet(
+        &self,
+        id: ObjectId,
+        planet: Planet,
+        actor: &str,
+    ) -> Result<Planet, CustomError> {
+        let mut state = self.lock();
+        let planet_before = state
+            .planets
+            .get(&id)
+            .filter(|planet| planet.deleted_at.is_none())
+            .cloned()
+            .ok_or(NotFound {
+                message: format!("Can't find a planet to update by id: {}", &id),
+            })?;
+
+        let updated_planet = Planet {
+            id: Some(id),
+            deleted_at: None,
+            ..planet
+        };
+        state.assert_name_is_unique(&updated_planet)?;
+        state.planets.insert(id, updated_planet.clone());
+        state.record_revision(
+            RevisionOperation::Update,
+            actor,
+            Some(&planet_before),
+            Some(&updated_planet),
+        );
+
+        Ok(updated_planet)
+    }
+
+    async fn upsert_planet_by_name(
+        &self,
+        planet: Planet,
+        event: Option<OutboxEvent>,
+        actor: &str,
+    ) -> Result<(Planet, bool), CustomError> {
+        let mut state = self.lock();
+        let existing_planet = state.planets.values_mut().find(|existing_planet| {
+            existing_planet.name == planet.name && existing_planet.deleted_at.is_none()
+        });
+
+        match existing_planet {
+            Some(existing_planet) => {
+                let planet_before = existing_planet.clone();
+                *existing_planet = Planet {
+                    id: planet_before.id,
+                    ..planet
+                };
+                let updated_planet = existing_planet.clone();
+                state.record_revision(
+                    RevisionOperation::Update,
+                    actor,
+                    Some(&planet_before),
+                    Some(&updated_planet),
+                );
+                Ok((updated_planet, false))
+            }
+            None => {
+                let created_planet = state.insert_planet(planet, actor)?;
+                state.outbox.extend(event);
+                Ok((created_planet, true))
+            }
+        }
+    }
+
+    async fn delete_planet(&self, id: ObjectId, actor: &str) -> Result<(), CustomError> {
+        let mut state = self.lock();
+        let planet = state
+            .planets
+            .get_mut(&id)
+            .filter(|planet| planet.deleted_at.is_none())
+            .ok_or(NotFound {
+                message: format!("Can't delete a planet by id: {}", id),
+            })?;
+
+        let deleted_planet = planet.clone();
+        planet.deleted_at = Some(mongodb::bson::DateTime::now());
+        state.record_revision(
+            RevisionOperation::Delete,
+            actor,
+            Some(&deleted_planet),
+            None,
+        );
+
+        Ok(())
+    }
+
+    async fn restore_planet(&self, id: ObjectId, actor: &str) -> Result<Planet, CustomError> {
+        let mut state = self.lock();
+        let deleted_planet = state
+            .planets
+            .get(&id)
+            .filter(|planet| planet.deleted_at.is_some())
+            .cloned()
+            .ok_or(NotFound {
+                message: format!("Can't find a deleted planet by id: {}", id),
+            })?;
+
+        let restored_planet = Planet {
+            deleted_at: None,
+            ..deleted_planet.clone()
+        };
+        state.assert_name_is_unique(&restored_planet)?;
+        state.planets.insert(id, restored_planet.clone());
+        state.record_revision(
+            RevisionOperation::Restore,
+            actor,
+            Some(&deleted_planet),
+            Some(&restored_planet),
+        );
+
+        Ok(restored_planet)
+    }
+
+    async fn get_revisions(&self, planet_id: ObjectId) -> Result<Vec<Revision>, CustomError> {
+        let mut revisions: Vec<Revision> = self
+            .lock()
+            .revisions
+            .iter()
+            .filter(|revision| revision.planet_id == planet_id)
+            .cloned()
+            .collect();
+        revisions.sort_by_key(|revision| revision.revision);
+
+        Ok(revisions)
+    }
+
+    async fn revert_planet(
+        &self,
+        id: ObjectId,
+        revision: i64,
+        actor: &str,
+    ) -> Result<Planet, CustomError> {
+        let mut state = self.lock();
+        let target_state = state
+            .revisions
+            .iter()
+            .find(|r| r.planet_id == id && r.revision == revision)
+            .ok_or(NotFound {
+                message: format!(
+                    "Can't find revision {} of a planet by id: {}",
+                    revision, &id
+                ),
+            })?
+            .after
+            .clone()
+            .ok_or(NotFound {
+                message: format!(
+                    "Revision {} of a planet by id: {} has no state",
+                    revision, &id
+                ),
+            })?;
+        let reverted_planet: Planet = mongodb::bson::from_document(target_state)?;
+        state.assert_name_is_unique(&reverted_planet)?;
+
+        let planet_before = state.planets.insert(id, reverted_planet.clone());
+        state.record_revision(
+            RevisionOperation::Revert,
+            actor,
+            planet_before.as_ref(),
+            Some(&reverted_planet),
+        );
+
+        Ok(reverted_planet)
+    }
+
+    async fn ping(&self) -> Result<(), CustomError> {
+        Ok(())
+    }
+}
+
+/// Expired entries are removed lazily when they are read
+#[derive(Default)]
+pub struct InMemoryCache {
+    entries: Mutex<HashMap<String, (Vec<u8>, Instant)>>,
+}
+
+impl InMemoryCache {
+    pub fn new() -> Self {
+        InMemoryCache::default()
+    }
+
+    fn get_value(&self, key: &str) -> Option<Vec<u8>> {
+        let mut entries = self.entries.lock().expect("Can't lock cache entries");
+        match entries.get(key) {
+            Some((_, expires_at)) if *expires_at <= Instant::now() => {
+                entries.remove(key);
+                None
+            }
+            entry => entry.map(|(value, _)| value.clone()),
+        }
+    }
+}
+
+#[async_trait]
+impl Cache for InMemoryCache {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CustomError> {
+        Ok(self.get_value(key))
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, CustomError> {
+        Ok(keys.iter().map(|key| self.get_value(key)).collect())
+    }
+
+    async fn set_many(
+        &self,
+        entries: Vec<(String, Vec<u8>)>,
+        ttl: Duration,
+    ) -> Result<(), CustomError> {
+        let expires_at = Instant::now() + ttl;
+        self.entries
+            .lock()
+            .expect("Can't lock cache entries")
+            .extend(
+                entries
+                    .into_iter()
+                    .map(|(key, value)| (key, (value, expires_at))),
+            );
+
+        Ok(())
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<(), CustomError> {
+        let mut entries = self.entries.lock().expect("Can't lock cache entries");
+        for key in keys {
+            entries.remove(key);
+        }
+
+        Ok(())
+    }
+
+    async fn delete_by_prefix(&self, prefix: &str) -> Result<u64, CustomError> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("Can't lock cache entries");
+        let mut deleted = 0;
+        entries.retain(|key, (_, expires_at)| {
+            let is_deleted = key.starts_with(prefix);
+            // expired entries don't exist for clients of the cache
+            if is_deleted && *expires_at > now {
+                deleted += 1;
+            }
+            !is_deleted
+        });
+
+        Ok(deleted)
+    }
+
+    async fn ping(&self) -> Result<(), CustomError> {
+        Ok(())
+    }
+}
+
+/// Delivers events to the broadcaster right away like the Pub/Sub listener does
+pub struct InMemoryEventPublisher {
+    broadcaster: Data<Mutex<Broadcaster>>,
+    published_event_ids: Mutex<HashSet<String>>,
+    published_payloads: Mutex<Vec<String>>,
+}
+
+impl InMemoryEventPublisher {
+    pub fn new(broadcaster: Data<Mutex<Broadcaster>>) -> Self {
+        InMemoryEventPublisher {
+            broadcaster,
+            published_event_ids: Mutex::new(HashSet::new()),
+            published_payloads: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Payloads as they were published, before they are delivered to clients
+    pub fn published_payloads(&self) -> Vec<String> {
+        self.published_payloads
+            .lock()
+            .expect("Can't lock published payloads")
+            .clone()
+    }
+}
+
+#[async_trait]
+impl EventPublisher for InMemoryEventPublisher {
+    async fn publish_planet_event(
+        &self,
+        event_id: &str,
+        payload: &str,
+    ) -> Result<bool, CustomError> {
+        let is_new = self
+            .published_event_ids
+            .lock()
+            .expect("Can't lock published event ids")
+            .insert(event_id.to_string());
+
+        if is_new {
+            self.published_payloads
+                .lock()
+                .expect("Can't lock published payloads")
+                .push(payload.to_string());
+            self.broadcaster
+                .lock()
+                .expect("Can't lock broadcaster")
+                .send_planet_created(payload);
+        }
+
+        Ok(is_new)
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryRateLimiterStore {
+    counters: Mutex<HashMap<String, (u64, Instant)>>,
+}
+
+impl InMemoryRateLimiterStore {
+    pub fn new() -> Self {
+        InMemoryRateLimiterStore::default()
+    }
+}
+
+#[async_trait]
+impl RateLimiterStore for InMemoryRateLimiterStore {
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, CustomError> {
+        let now = Instant::now();
+        let mut counters = self
+            .counters
+            .lock()
+            .expect("Can't lock rate limit counters");
+        let counter = counters.entry(key.to_string()).or_insert((0, now));
+        if counter.1 <= now {
+            counter.0 = 0;
+        }
+        // like EXPIRE, every increment prolongs the counter
+        *counter = (counter.0 + 1, now + ttl);
+
+        Ok(counter.0)
+    }
+
+    async fn get(&self, key: &str) -> Result<u64, CustomError> {
+        let counters = self
+            .counters
+            .lock()
+            .expect("Can't lock rate limit counters");
+        match counters.get(key) {
+            Some((count, expires_at)) if *expires_at > Instant::now() => Ok(*count),
+            _ => Ok(0),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CustomError> {
+        self.counters
+            .lock()
+            .expect("Can't lock rate limit counters")
+            .remove(key);
+
+        Ok(())
+    }
+}
@@ -1,26 +1,184 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use chrono::Utc;
 use lazy_static::lazy_static;
-use prometheus::{opts, register_histogram_vec, register_int_counter_vec, register_int_gauge};
-use prometheus::{HistogramVec, IntCounterVec, IntGauge};
+use prometheus::proto::LabelPair;
+use prometheus::{
+    opts, register_gauge_vec, register_histogram_vec, register_int_counter,
+    register_int_counter_vec, register_int_gauge, register_int_gauge_vec,
+};
+use prometheus::{GaugeVec, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec};
 
 const HTTP_RESPONSE_TIME_CUSTOM_BUCKETS: &[f64; 14] = &[
     0.0005, 0.0008, 0.00085, 0.0009, 0.00095, 0.001, 0.00105, 0.0011, 0.00115, 0.0012, 0.0015,
     0.002, 0.003, 1.0,
 ];
+const HTTP_RESPONSE_SIZE_BUCKETS: &[f64; 8] = &[
+    64.0, 256.0, 1024.0, 4096.0, 16384.0, 65536.0, 262144.0, 1048576.0,
+];
+const DEPENDENCY_LATENCY_BUCKETS: &[f64; 12] = &[
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
 
 lazy_static! {
     pub static ref HTTP_REQUESTS_TOTAL: IntCounterVec = register_int_counter_vec!(
         opts!("http_requests_total", "HTTP requests total"),
-        &["method", "path"]
+        &["method", "path", "version", "status"]
     )
     .expect("Can't create a metric");
+    pub static ref HTTP_REQUESTS_IN_FLIGHT: IntGauge = register_int_gauge!(opts!(
+        "http_requests_in_flight",
+        "HTTP requests that are being handled"
+    ))
+    .expect("Can't create a metric");
     pub static ref HTTP_CONNECTED_SSE_CLIENTS: IntGauge =
         register_int_gauge!(opts!("http_connected_sse_clients", "Connected SSE clients"))
             .expect("Can't create a metric");
+    pub static ref REDIS_PUBSUB_LISTENER_UP: IntGauge = register_int_gauge!(opts!(
+        "redis_pubsub_listener_up",
+        "Whether Redis Pub/Sub listener is subscribed (1) or reconnecting (0)"
+    ))
+    .expect("Can't create a metric");
+    pub static ref READINESS_DEPENDENCY_UP: IntGaugeVec = register_int_gauge_vec!(
+        opts!(
+            "readiness_dependency_up",
+            "Whether a dependency passed the last readiness check (1) or not (0)"
+        ),
+        &["dependency"]
+    )
+    .expect("Can't create a metric");
+    pub static ref READINESS_DEPENDENCY_LATENCY_SECONDS: GaugeVec = register_gauge_vec!(
+        opts!(
+            "readiness_dependency_latency_seconds",
+            "Latency of a dependency in the last readiness check"
+        ),
+        &["dependency"]
+    )
+    .expect("Can't create a metric");
     pub static ref HTTP_RESPONSE_TIME_SECONDS: HistogramVec = register_histogram_vec!(
         "http_response_time_seconds",
         "HTTP response times",
-        &["method", "path"],
+        &["method", "path", "status"],
         HTTP_RESPONSE_TIME_CUSTOM_BUCKETS.to_vec()
     )
     .expect("Can't create a metric");
+    pub static ref HTTP_RESPONSE_SIZE_BYTES: HistogramVec = register_histogram_vec!(
+        "http_response_size_bytes",
+        "Sizes of HTTP response bodies; streamed responses aren't observed",
+        &["method", "path"],
+        HTTP_RESPONSE_SIZE_BUCKETS.to_vec()
+    )
+    .expect("Can't create a metric");
+    pub static ref MONGODB_OPERATION_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "mongodb_operation_duration_seconds",
+        "Durations of MongoDB commands, e.g. find, insert, update or delete",
+        &["operation"],
+        DEPENDENCY_LATENCY_BUCKETS.to_vec()
+    )
+    .expect("Can't create a metric");
+    pub static ref REDIS_COMMAND_DURATION_SECONDS: HistogramVec = register_histogram_vec!(
+        "redis_command_duration_seconds",
+        "Durations of Redis commands; a pipeline is labeled by its main command",
+        &["command"],
+        DEPENDENCY_LATENCY_BUCKETS.to_vec()
+    )
+    .expect("Can't create a metric");
+    pub static ref CACHE_HITS_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!("cache_hits_total", "Values found in Redis cache"),
+        &["cache"]
+    )
+    .expect("Can't create a metric");
+    pub static ref CACHE_MISSES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!("cache_misses_total", "Values not found in Redis cache"),
+        &["cache"]
+    )
+    .expect("Can't create a metric");
+    pub static ref RATE_LIMIT_REJECTIONS_TOTAL: IntCounter = register_int_counter!(opts!(
+        "rate_limit_rejections_total",
+        "Requests rejected because of the rate limit"
+    ))
+    .expect("Can't create a metric");
+    pub static ref REDIS_PUBSUB_MESSAGES_TOTAL: IntCounterVec = register_int_counter_vec!(
+        opts!(
+            "redis_pubsub_messages_total",
+            "Planet events published to or received from Redis Pub/Sub"
+        ),
+        &["direction"]
+    )
+    .expect("Can't create a metric");
+}
+
+/// An observation of a histogram bucket that links the bucket to a trace
+#[derive(Clone, Debug)]
+pub struct Exemplar {
+    pub trace_id: String,
+    pub value: f64,
+    /// Seconds since the Unix epoch
+    pub timestamp: f64,
+}
+
+// name of a histogram, its labels sorted by name and upper bound of a bucket
+type ExemplarKey = (String, Vec<(String, String)>, u64);
+
+lazy_static! {
+    // only the last observation of each bucket is kept
+    static ref EXEMPLARS: Mutex<HashMap<ExemplarKey, Exemplar>> = Mutex::new(HashMap::new());
+}
+
+pub fn observe_http_response_time(
+    method: &str,
+    path: &str,
+    status: &str,
+    seconds: f64,
+    trace_id: Option<String>,
+) {
+    HTTP_RESPONSE_TIME_SECONDS
+        .with_label_values(&[method, path, status])
+        .observe(seconds);
+
+    if let Some(trace_id) = trace_id {
+        let upper_bound = HTTP_RESPONSE_TIME_CUSTOM_BUCKETS
+            .iter()
+            .copied()
+            .find(|upper_bound| seconds <= *upper_bound)
+            .unwrap_or(f64::INFINITY);
+        let labels = [("method", method), ("path", path), ("status", status)]
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        let exemplar = Exemplar {
+            trace_id,
+            value: seconds,
+            timestamp: Utc::now().timestamp_millis() as f64 / 1000.0,
+        };
+
+        EXEMPLARS.lock().expect("Can't lock exemplars").insert(
+            exemplar_key("http_response_time_seconds", labels, upper_bound),
+            exemplar,
+        );
+    }
+}
+
+/// Returns the last observation of a bucket of the histogram that has a trace id
+pub fn get_exemplar(histogram: &str, labels: &[LabelPair], upper_bound: f64) -> Option<Exemplar> {
+    let labels = labels
+        .iter()
+        .map(|label| (label.get_name().to_string(), label.get_value().to_string()))
+        .collect();
+
+    EXEMPLARS
+        .lock()
+        .expect("Can't lock exemplars")
+        .get(&exemplar_key(histogram, labels, upper_bound))
+        .cloned()
+}
+
+fn exemplar_key(
+    histogram: &str,
+    mut labels: Vec<(String, String)>,
+    upper_bound: f64,
+) -> ExemplarKey {
+    labels.sort();
+    (histogram.to_string(), labels, upper_bound.to_bits())
 }
@@ -1,12 +1,16 @@
+use std::collections::HashSet;
 use std::str::FromStr;
+use std::time::Duration;
 
 use crate::dto::{PlanetDto, SatelliteDto};
-use chrono::Utc;
+use crate::errors::CustomError;
+use async_graphql::Enum;
 use mongodb::bson::{self, doc, oid::ObjectId, Document};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use utoipa::ToSchema;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Planet {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
@@ -14,9 +18,11 @@ pub struct Planet {
     pub r#type: PlanetType,
     pub mean_radius: f32,
     pub satellites: Option<Vec<Satellite>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<mongodb::bson::DateTime>,
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Debug, ToSchema, Enum)]
 pub enum PlanetType {
     TerrestrialPlanet,
     GasGiant,
@@ -24,31 +30,135 @@ pub enum PlanetType {
     DwarfPlanet,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 pub struct Satellite {
     pub name: String,
     pub first_spacecraft_landing_date: Option<mongodb::bson::DateTime>,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlanetStats {
+    pub count_by_type: Vec<PlanetTypeCount>,
+    pub min_mean_radius: Option<f64>,
+    pub max_mean_radius: Option<f64>,
+    pub avg_mean_radius: Option<f64>,
+    pub total_satellites: i64,
+    pub earliest_landing_date: Option<mongodb::bson::DateTime>,
+    pub latest_landing_date: Option<mongodb::bson::DateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlanetTypeCount {
+    #[serde(rename = "_id")]
+    pub r#type: PlanetType,
+    pub count: i64,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub struct Revision {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub planet_id: ObjectId,
+    pub revision: i64,
+    pub operation: RevisionOperation,
+    pub actor: String,
+    pub timestamp: mongodb::bson::DateTime,
+    pub before: Option<Document>,
+    pub after: Option<Document>,
+    pub diff: Document,
+}
+
+#[derive(Copy, Clone, Eq, PartialEq, Serialize, Deserialize, Debug)]
+pub enum RevisionOperation {
+    Create,
+    Update,
+    Delete,
+    Restore,
+    Revert,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct OutboxEvent {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub payload: String,
+    pub created_at: mongodb::bson::DateTime,
+}
+
+impl Planet {
+    /// Returns descriptions of problems with the planet's data; the data is valid if it's empty
+    pub fn validate(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        if self.name.trim().is_empty() {
+            problems.push(String::from("name is empty"));
+        }
+        if !self.mean_radius.is_finite() || self.mean_radius <= 0.0 {
+            problems.push(format!("mean_radius is not positive: {}", self.mean_radius));
+        }
+
+        let now = bson::DateTime::now();
+        let mut satellite_names = HashSet::new();
+        for satellite in self.satellites.iter().flatten() {
+            if satellite.name.trim().is_empty() {
+                problems.push(String::from("satellite name is empty"));
+            } else if !satellite_names.insert(satellite.name.as_str()) {
+                problems.push(format!("satellite {} is listed twice", satellite.name));
+            }
+            if satellite
+                .first_spacecraft_landing_date
+                .is_some_and(|date| date > now)
+            {
+                problems.push(format!(
+                    "first_spacecraft_landing_date of satellite {} is in the future",
+                    satellite.name
+                ));
+            }
+        }
+
+        problems
+    }
+}
+
+impl OutboxEvent {
+    pub fn new(payload: String) -> Self {
+        OutboxEvent {
+            id: ObjectId::new(),
+            payload,
+            created_at: mongodb::bson::DateTime::now(),
+        }
+    }
+}
+
 impl From<&Planet> for Document {
     fn from(source: &Planet) -> Self {
         bson::to_document(source).expect("Can't convert a planet to Document")
     }
 }
 
-impl From<PlanetDto> for Planet {
-    fn from(source: PlanetDto) -> Self {
-        Planet {
-            id: source
-                .id
-                .map(|id| ObjectId::from_str(id.as_str()).expect("Can't convert to ObjectId")),
+/// Fails if the id of a planet isn't a valid `ObjectId`
+impl TryFrom<PlanetDto> for Planet {
+    type Error = CustomError;
+
+    fn try_from(source: PlanetDto) -> Result<Self, Self::Error> {
+        let id = source
+            .id
+            .map(|id| {
+                ObjectId::from_str(id.as_str()).map_err(|_| CustomError::BadRequest {
+                    message: format!("Invalid id of a planet: {}", id),
+                })
+            })
+            .transpose()?;
+
+        Ok(Planet {
+            id,
             name: source.name,
             r#type: source.r#type,
-            mean_radius: source.mean_radius,
+            mean_radius: source.mean_radius.to_kilometers(),
             satellites: source
                 .satellites
                 .map(|satellites| satellites.into_iter().map(Satellite::from).collect()),
-        }
+            deleted_at: None,
+        })
     }
 }
 
@@ -58,8 +168,9 @@ impl From<SatelliteDto> for Satellite {
             name: source.name,
             first_spacecraft_landing_date: source.first_spacecraft_landing_date.map(|d| {
                 mongodb::bson::DateTime::from_millis(
-                    chrono::Date::<Utc>::from_utc(d, Utc)
-                        .and_hms(0, 0, 0)
+                    d.and_hms_opt(0, 0, 0)
+                        .expect("Midnight is a valid time")
+                        .and_utc()
                         .timestamp_millis(),
                 )
             }),
@@ -67,6 +178,14 @@ impl From<SatelliteDto> for Satellite {
     }
 }
 
+#[derive(Debug)]
+pub struct DependencyHealth {
+    pub dependency: &'static str,
+    pub latency: Duration,
+    // is empty if the dependency is up
+    pub error: Option<String>,
+}
+
 impl fmt::Display for PlanetType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{:?}", self)
@@ -0,0 +1,234 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::http::header::{self, Header};
+use actix_web::http::StatusCode;
+use actix_web::web::Bytes;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::dto::{PlanetCsvRecord, PlanetDto, Units};
+use crate::errors::CustomError;
+use crate::model::Planet;
+
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+const MESSAGE_PACK_CONTENT_TYPE: &str = "application/msgpack";
+const MESSAGE_PACK_LEGACY_CONTENT_TYPE: &str = "application/x-msgpack";
+
+/// Formats in which planets can be represented in requests and responses
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Representation {
+    Json,
+    Cbor,
+    MessagePack,
+    Csv,
+}
+
+impl Representation {
+    /// Picks the most preferred supported format from `Accept` header; JSON is used by default
+    pub fn from_accept(req: &HttpRequest) -> Result<Self, CustomError> {
+        let accept = match header::Accept::parse(req) {
+            Ok(accept) if !accept.is_empty() => accept,
+            _ => return Ok(Representation::Json),
+        };
+
+        accept
+            .ranked()
+            .iter()
+            .find_map(|mime| match (mime.type_(), mime.subtype()) {
+                (mime::STAR, mime::STAR) | (mime::APPLICATION, mime::STAR) => {
+                    Some(Representation::Json)
+                }
+                (mime::TEXT, mime::STAR) => Some(Representation::Csv),
+                _ => Representation::from_essence(mime.essence_str()),
+            })
+            .ok_or(CustomError::NotAcceptable {
+                message: String::from(
+                    "Supported formats are application/json, application/cbor, application/msgpack and text/csv",
+                ),
+            })
+    }
+
+    /// Detects the format of a request body by `Content-Type` header; JSON is used by default
+    pub fn from_content_type(req: &HttpRequest) -> Result<Self, CustomError> {
+        match req.mime_type() {
+            Ok(Some(mime)) => Representation::from_essence(mime.essence_str()).ok_or(
+                CustomError::UnsupportedMediaType {
+                    message: format!("Unsupported content type: {}", mime),
+                },
+            ),
+            _ => Ok(Representation::Json),
+        }
+    }
+
+    fn from_essence(essence: &str) -> Option<Self> {
+        match essence {
+            "application/json" => Some(Representation::Json),
+            CBOR_CONTENT_TYPE => Some(Representation::Cbor),
+            MESSAGE_PACK_CONTENT_TYPE | MESSAGE_PACK_LEGACY_CONTENT_TYPE => {
+                Some(Representation::MessagePack)
+            }
+            "text/csv" => Some(Representation::Csv),
+            _ => None,
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            Representation::Json => "application/json",
+            Representation::Cbor => CBOR_CONTENT_TYPE,
+            Representation::MessagePack => MESSAGE_PACK_CONTENT_TYPE,
+            Representation::Csv => "text/csv",
+        }
+    }
+
+    fn serialize<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, CustomError> {
+        match self {
+            Representation::Json => Ok(serde_json::to_vec(value)?),
+            Representation::Cbor => {
+                let mut buffer = vec![];
+                ciborium::ser::into_writer(value, &mut buffer)
+                    .map_err(|_| CustomError::InternalError)?;
+                Ok(buffer)
+            }
+            Representation::MessagePack => {
+                rmp_serde::to_vec_named(value).map_err(|_| CustomError::InternalError)
+            }
+            Representation::Csv => unreachable!("CSV is serialized from flat records"),
+        }
+    }
+
+    fn deserialize<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, String> {
+        match self {
+            Representation::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            Representation::Cbor => ciborium::de::from_reader(body).map_err(|e| e.to_string()),
+            Representation::MessagePack => rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+            Representation::Csv => unreachable!("CSV is deserialized to flat records"),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct UnitsQueryParams {
+    units: Option<Units>,
+}
+
+/// Unit system is taken from `units` query parameter of any planet endpoint
+pub fn get_units(req: &HttpRequest) -> Result<Option<Units>, CustomError> {
+    web::Query::<UnitsQueryParams>::from_query(req.query_string())
+        .map(|query_params| query_params.into_inner().units)
+        .map_err(|e| CustomError::BadRequest {
+            message: format!("Can't parse units: {}", e),
+        })
+}
+
+/// CSV is read in kilometers, so lengths in other units couldn't be sent back
+pub fn assert_csv_units(units: Option<Units>) -> Result<(), CustomError> {
+    match units {
+        None => Ok(()),
+        Some(_) => Err(CustomError::BadRequest {
+            message: String::from("Units aren't supported in CSV, lengths are in kilometers"),
+        }),
+    }
+}
+
+pub fn planet_response(
+    req: &HttpRequest,
+    status: StatusCode,
+    planet: Planet,
+) -> Result<HttpResponse, CustomError> {
+    planets_response(req, status, vec![planet], false)
+}
+
+pub fn planet_list_response(
+    req: &HttpRequest,
+    status: StatusCode,
+    planets: Vec<Planet>,
+) -> Result<HttpResponse, CustomError> {
+    planets_response(req, status, planets, true)
+}
+
+fn planets_response(
+    req: &HttpRequest,
+    status: StatusCode,
+    planets: Vec<Planet>,
+    is_list: bool,
+) -> Result<HttpResponse, CustomError> {
+    let representation = Representation::from_accept(req)?;
+    let units = get_units(req)?;
+
+    let body = match representation {
+        // the same table is used for a single planet and for a list
+        Representation::Csv => {
+            assert_csv_units(units)?;
+            let mut writer = csv::Writer::from_writer(vec![]);
+            for planet in planets {
+                writer.serialize(PlanetCsvRecord::from(planet))?;
+            }
+            writer
+                .into_inner()
+                .map_err(|_| CustomError::InternalError)?
+        }
+        _ => {
+            let mut planets = planets
+                .into_iter()
+                .map(|planet| PlanetDto::with_units(planet, units));
+            if is_list {
+                representation.serialize(&planets.collect::<Vec<_>>())?
+            } else {
+                let planet = planets.next().ok_or(CustomError::InternalError)?;
+                representation.serialize(&planet)?
+            }
+        }
+    };
+
+    Ok(HttpResponse::build(status)
+        .insert_header((header::CONTENT_TYPE, representation.content_type()))
+        .insert_header((header::VARY, "Accept"))
+        .body(body))
+}
+
+/// Extracts a planet from a request body in any of the supported formats
+pub struct PlanetPayload(pub Planet);
+
+impl PlanetPayload {
+    pub fn into_inner(self) -> Planet {
+        self.0
+    }
+
+    fn decode(req: &HttpRequest, body: &[u8]) -> Result<Self, CustomError> {
+        let representation = Representation::from_content_type(req)?;
+
+        let planet_dto = match representation {
+            Representation::Csv => csv::Reader::from_reader(body)
+                .deserialize::<PlanetCsvRecord>()
+                .next()
+                .ok_or_else(|| String::from("CSV doesn't contain a planet"))
+                .and_then(|record| record.map_err(|e| e.to_string()))
+                .map(PlanetDto::from),
+            _ => representation.deserialize(body),
+        }
+        .map_err(|e| CustomError::BadRequest {
+            message: format!("Can't parse a planet: {}", e),
+        })?;
+
+        Ok(PlanetPayload(Planet::try_from(planet_dto)?))
+    }
+}
+
+impl FromRequest for PlanetPayload {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let req = req.clone();
+        let body = Bytes::from_request(&req, payload);
+
+        Box::pin(async move {
+            let body = body.await?;
+            Ok(PlanetPayload::decode(&req, &body)?)
+        })
+    }
+}
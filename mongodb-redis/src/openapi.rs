@@ -0,0 +1,28 @@
+use utoipa::OpenApi;
+
+use crate::handlers;
+
+/// OpenAPI 3 document generated from handlers and DTOs. Writing endpoints are described
+/// even though they are only available if `ENABLE_WRITING_HANDLERS` is set
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Solar System info",
+        description = "Planets of the Solar System",
+        license(name = "MIT")
+    ),
+    paths(
+        handlers::get_planets,
+        handlers::create_planet,
+        handlers::get_planet,
+        handlers::update_planet,
+        handlers::delete_planet,
+        handlers::get_image_of_planet,
+        handlers::sse,
+    ),
+    tags(
+        (name = "planets", description = "Planets are rate limited and cached in Redis"),
+        (name = "events", description = "Notifications about created planets"),
+    )
+)]
+pub struct ApiDoc;
@@ -0,0 +1,192 @@
+use std::fmt::Write;
+
+use prometheus::proto::{LabelPair, MetricFamily, MetricType};
+
+use crate::metrics::{self, Exemplar};
+
+pub const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Encodes metrics in OpenMetrics text format; buckets of histograms are supplemented with
+/// exemplars that contain trace ids
+pub fn encode(metric_families: &[MetricFamily]) -> String {
+    let mut output = String::new();
+
+    for family in metric_families {
+        let name = family.get_name();
+        let (family_name, family_type) = match family.get_field_type() {
+            // samples of counters have `_total` suffix, but their family doesn't
+            MetricType::COUNTER => (name.strip_suffix("_total").unwrap_or(name), "counter"),
+            MetricType::GAUGE => (name, "gauge"),
+            MetricType::HISTOGRAM => (name, "histogram"),
+            MetricType::SUMMARY => (name, "summary"),
+            MetricType::UNTYPED => (name, "unknown"),
+        };
+
+        writeln!(
+            output,
+            "# HELP {} {}",
+            family_name,
+            escape(family.get_help())
+        )
+        .unwrap();
+        writeln!(output, "# TYPE {} {}", family_name, family_type).unwrap();
+
+        for metric in family.get_metric() {
+            let labels = metric.get_label();
+            match family.get_field_type() {
+                MetricType::COUNTER => write_sample(
+                    &mut output,
+                    &format!("{}_total", family_name),
+                    labels,
+                    None,
+                    metric.get_counter().get_value(),
+                    None,
+                ),
+                MetricType::GAUGE => write_sample(
+                    &mut output,
+                    name,
+                    labels,
+                    None,
+                    metric.get_gauge().get_value(),
+                    None,
+                ),
+                MetricType::UNTYPED => write_sample(
+                    &mut output,
+                    name,
+                    labels,
+                    None,
+                    metric.get_untyped().get_value(),
+                    None,
+                ),
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let bucket_name = format!("{}_bucket", name);
+                    let upper_bounds = histogram
+                        .get_bucket()
+                        .iter()
+                        .map(|bucket| (bucket.get_upper_bound(), bucket.get_cumulative_count()))
+                        .chain([(f64::INFINITY, histogram.get_sample_count())]);
+
+                    for (upper_bound, count) in upper_bounds {
+                        write_sample(
+                            &mut output,
+                            &bucket_name,
+                            labels,
+                            Some(("le", upper_bound)),
+                            count as f64,
+                            metrics::get_exemplar(name, labels, upper_bound),
+                        );
+                    }
+                    write_sample(
+                        &mut output,
+                        &format!("{}_sum", name),
+                        labels,
+                        None,
+                        histogram.get_sample_sum(),
+                        None,
+                    );
+                    write_sample(
+                        &mut output,
+                        &format!("{}_count", name),
+                        labels,
+                        None,
+                        histogram.get_sample_count() as f64,
+                        None,
+                    );
+                }
+                MetricType::SUMMARY => {
+                    let summary = metric.get_summary();
+                    for quantile in summary.get_quantile() {
+                        write_sample(
+                            &mut output,
+                            name,
+                            labels,
+                            Some(("quantile", quantile.get_quantile())),
+                            quantile.get_value(),
+                            None,
+                        );
+                    }
+                    write_sample(
+                        &mut output,
+                        &format!("{}_sum", name),
+                        labels,
+                        None,
+                        summary.get_sample_sum(),
+                        None,
+                    );
+                    write_sample(
+                        &mut output,
+                        &format!("{}_count", name),
+                        labels,
+                        None,
+                        summary.get_sample_count() as f64,
+                        None,
+                    );
+                }
+            }
+        }
+    }
+
+    output.push_str("# EOF\n");
+    output
+}
+
+fn write_sample(
+    output: &mut String,
+    name: &str,
+    labels: &[LabelPair],
+    extra_label: Option<(&str, f64)>,
+    value: f64,
+    exemplar: Option<Exemplar>,
+) {
+    let mut label_values: Vec<String> = labels
+        .iter()
+        .map(|label| format!("{}=\"{}\"", label.get_name(), escape(label.get_value())))
+        .collect();
+    if let Some((label_name, label_value)) = extra_label {
+        label_values.push(format!("{}=\"{}\"", label_name, format_bound(label_value)));
+    }
+
+    output.push_str(name);
+    if !label_values.is_empty() {
+        write!(output, "{{{}}}", label_values.join(",")).unwrap();
+    }
+    write!(output, " {}", format_value(value)).unwrap();
+    if let Some(exemplar) = exemplar {
+        write!(
+            output,
+            " # {{trace_id=\"{}\"}} {} {}",
+            exemplar.trace_id,
+            format_value(exemplar.value),
+            exemplar.timestamp
+        )
+        .unwrap();
+    }
+    output.push('\n');
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        String::from("NaN")
+    } else if value.is_infinite() {
+        String::from(if value > 0.0 { "+Inf" } else { "-Inf" })
+    } else {
+        value.to_string()
+    }
+}
+
+// bounds of buckets and quantiles are floats, e.g. `1.0` rather than `1`
+fn format_bound(value: f64) -> String {
+    if value.is_finite() && value.fract() == 0.0 {
+        format!("{:.1}", value)
+    } else {
+        format_value(value)
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,65 @@
+use std::time::Duration;
+
+use redis::aio::ConnectionManager;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, error};
+
+use crate::db::MongoDbClient;
+use crate::errors::CustomError;
+
+const RELAY_INTERVAL: Duration = Duration::from_millis(500);
+const RELAY_BATCH_SIZE: i64 = 100;
+
+/// Publishes events saved in `outbox` collection to Redis. An event is deleted from the outbox
+/// only after it is published, so it is delivered at least once; its id is used to skip duplicates
+pub fn start_outbox_relay(
+    mongodb_client: MongoDbClient,
+    redis_connection_manager: ConnectionManager,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(RELAY_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            if let Err(e) = relay_events(&mongodb_client, &redis_connection_manager).await {
+                error!("Can't relay outbox events: {}", e);
+            }
+        }
+    })
+}
+
+/// Relays events until the outbox is empty, e.g. on shutdown
+pub async fn flush_outbox(
+    mongodb_client: &MongoDbClient,
+    redis_connection_manager: &ConnectionManager,
+) -> Result<(), CustomError> {
+    while relay_events(mongodb_client, redis_connection_manager).await? > 0 {}
+    Ok(())
+}
+
+/// Returns the number of relayed events
+pub async fn relay_events(
+    mongodb_client: &MongoDbClient,
+    redis_connection_manager: &ConnectionManager,
+) -> Result<usize, CustomError> {
+    let events = mongodb_client.get_outbox_events(RELAY_BATCH_SIZE).await?;
+    let count = events.len();
+
+    for event in events {
+        let event_id = event.id.to_string();
+        let is_published = crate::redis::publish_planet_event(
+            &mut redis_connection_manager.clone(),
+            &event_id,
+            &event.payload,
+        )
+        .await?;
+        if !is_published {
+            debug!("Outbox event {} has already been published", event_id);
+        }
+
+        mongodb_client.delete_outbox_event(event.id).await?;
+    }
+
+    Ok(count)
+}
@@ -0,0 +1,29 @@
+use std::time::Duration;
+
+use chrono::Utc;
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{error, info};
+
+use crate::db::MongoDbClient;
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Permanently removes planets that have been soft deleted for longer than the retention period
+pub fn start_purge(mongodb_client: MongoDbClient, retention: chrono::Duration) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = time::interval(PURGE_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            let deleted_before =
+                mongodb::bson::DateTime::from_millis((Utc::now() - retention).timestamp_millis());
+
+            match mongodb_client.purge_deleted_planets(deleted_before).await {
+                Ok(0) => {}
+                Ok(count) => info!("Purged {} deleted planets", count),
+                Err(e) => error!("Can't purge deleted planets: {}", e),
+            }
+        }
+    })
+}
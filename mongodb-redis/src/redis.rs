@@ -1,46 +1,417 @@
-use actix_web::web::{Bytes, Data};
-use redis::{Client, FromRedisValue, RedisError};
-use redis::{RedisWrite, ToRedisArgs};
+use std::cmp;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use async_trait::async_trait;
+use redis::aio::{Connection, ConnectionLike, ConnectionManager, PubSub};
+use redis::streams::StreamRangeReply;
+use redis::{AsyncCommands, Client, RedisError, Script};
+use tokio::task::JoinHandle;
+use tokio::time;
 use tokio_stream::StreamExt;
+use tracing::{debug, error, info, warn};
 
 use crate::broadcaster::Broadcaster;
+use crate::dto::PlanetMessage;
 use crate::errors::CustomError;
-use crate::model::Planet;
-use crate::services::NEW_PLANETS_CHANNEL_NAME;
-use std::sync::Mutex;
+use crate::services::{NEW_PLANETS_CHANNEL_NAME, PLANET_EVENTS_STREAM_NAME};
+use crate::storage::{Cache, EventPublisher, RateLimiterStore};
+
+const PUBSUB_INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const PUBSUB_MAX_BACKOFF: Duration = Duration::from_secs(30);
+pub const PLANET_EVENT_PAYLOAD_FIELD: &str = "payload";
+// the field of a published message; must match the name of `PlanetMessage.stream_id`
+const PLANET_EVENT_STREAM_ID_FIELD: &str = "stream_id";
+const PUBLISHED_EVENT_KEY_PREFIX: &str = "published_event";
+const PUBLISHED_EVENT_TTL_SECONDS: usize = 24 * 60 * 60;
+const PLANET_EVENTS_STREAM_MAX_LEN: usize = 1000;
+const SCAN_BATCH_SIZE: usize = 500;
+
+// an event is appended to the stream so that the Pub/Sub listener can replay it after a reconnect;
+// the ID of the stream entry is added to the published message, so the listener knows where to
+// replay from; the marker key prevents publishing of the same event twice if it is retried
+const PUBLISH_PLANET_EVENT_SCRIPT: &str = r#"
+if redis.call('SET', KEYS[1], 1, 'NX', 'EX', ARGV[1]) then
+    local stream_id = redis.call('XADD', KEYS[2], 'MAXLEN', '~', ARGV[2], '*', ARGV[3], ARGV[4])
+    local message = cjson.decode(ARGV[4])
+    message[ARGV[6]] = stream_id
+    redis.call('PUBLISH', ARGV[5], cjson.encode(message))
+    return 1
+end
+return 0
+"#;
 
 pub async fn create_client(redis_uri: String) -> Result<Client, RedisError> {
-    Ok(Client::open(redis_uri)?)
+    Client::open(redis_uri)
 }
 
+/// Returns the handle of the listener task, so that its liveness can be checked
 pub async fn start_pubsub(
     redis_client: &Client,
     broadcaster: Data<Mutex<Broadcaster>>,
-) -> Result<(), CustomError> {
+) -> Result<JoinHandle<()>, CustomError> {
+    // the first subscription is done eagerly so that the application fails fast on startup
+    let mut pubsub_con = subscribe(redis_client).await?;
+    let mut position = StreamPosition::at_end(redis_client).await?;
+    let redis_client = redis_client.clone();
+
+    let listener = tokio::spawn(async move {
+        loop {
+            crate::metrics::REDIS_PUBSUB_LISTENER_UP.set(1);
+
+            let mut messages = pubsub_con.on_message();
+            while let Some(msg) = messages.next().await {
+                match msg.get_payload::<String>() {
+                    Ok(payload) => {
+                        // events replayed after a reconnect can be received via Pub/Sub too
+                        let is_new = match stream_id_of(&payload) {
+                            Some(stream_id) => position.advance(&stream_id),
+                            None => true,
+                        };
+                        if is_new {
+                            broadcast_planet_event(&broadcaster, &payload);
+                        }
+                    }
+                    Err(e) => error!("Can't get payload of Pub/Sub message: {}", e),
+                }
+            }
+            drop(messages);
+
+            crate::metrics::REDIS_PUBSUB_LISTENER_UP.set(0);
+            warn!("Redis Pub/Sub connection is lost, trying to resubscribe");
+
+            pubsub_con = resubscribe(&redis_client).await;
+            info!("Redis Pub/Sub connection is restored");
+
+            // events published while the listener was disconnected are read from the stream
+            if let Err(e) = replay_planet_events(&redis_client, &mut position, &broadcaster).await {
+                error!("Can't replay planet events: {}", e);
+            }
+        }
+    });
+
+    Ok(listener)
+}
+
+/// Subscribes to the channel of planet events
+pub async fn subscribe(redis_client: &Client) -> Result<PubSub, RedisError> {
     let mut pubsub_con = redis_client.get_async_connection().await?.into_pubsub();
     pubsub_con.subscribe(NEW_PLANETS_CHANNEL_NAME).await?;
+    Ok(pubsub_con)
+}
+
+async fn resubscribe(redis_client: &Client) -> PubSub {
+    let mut backoff = PUBSUB_INITIAL_BACKOFF;
 
-    tokio::spawn(async move {
-        while let Some(msg) = pubsub_con.on_message().next().await {
-            let payload = msg.get_payload().expect("Can't get payload of message");
-            let payload: String =
-                FromRedisValue::from_redis_value(&payload).expect("Can't convert from Redis value");
-            let msg = Bytes::from(format!("data: Planet created: {:?}\n\n", payload));
-            broadcaster
-                .lock()
-                .expect("Can't lock broadcaster")
-                .send(msg);
+    loop {
+        time::sleep(backoff).await;
+
+        match subscribe(redis_client).await {
+            Ok(pubsub_con) => return pubsub_con,
+            Err(e) => {
+                error!(
+                    "Can't resubscribe to Redis Pub/Sub, next attempt in {:?}: {}",
+                    backoff, e
+                );
+                backoff = cmp::min(backoff * 2, PUBSUB_MAX_BACKOFF);
+            }
         }
-    });
+    }
+}
+
+async fn replay_planet_events(
+    redis_client: &Client,
+    position: &mut StreamPosition,
+    broadcaster: &Data<Mutex<Broadcaster>>,
+) -> Result<(), CustomError> {
+    let mut con: Connection = redis_client.get_async_connection().await?;
+
+    let reply: StreamRangeReply = observe(
+        "XRANGE",
+        con.xrange(PLANET_EVENTS_STREAM_NAME, position.range_start(), "+"),
+    )
+    .await?;
+
+    debug!("Replaying {} planet events", reply.ids.len());
+    for stream_id in reply.ids {
+        if !position.advance(&stream_id.id) {
+            continue;
+        }
+        match stream_id.get::<String>(PLANET_EVENT_PAYLOAD_FIELD) {
+            Some(payload) => broadcast_planet_event(broadcaster, &payload),
+            None => error!("Planet event {} has no payload", stream_id.id),
+        }
+    }
 
     Ok(())
 }
 
-impl ToRedisArgs for &Planet {
-    fn write_redis_args<W>(&self, out: &mut W)
-    where
-        W: ?Sized + RedisWrite,
-    {
-        out.write_arg_fmt(serde_json::to_string(self).expect("Can't serialize Planet as string"))
+/// ID of the last handled entry of the stream of planet events. IDs are assigned by Redis, so
+/// unlike timestamps of the application they don't depend on its clock
+#[derive(Debug, Default)]
+pub struct StreamPosition {
+    last_id: Option<(u64, u64)>,
+}
+
+impl StreamPosition {
+    /// Starts after the last entry that is currently in the stream
+    pub async fn at_end(redis_client: &Client) -> Result<Self, CustomError> {
+        let mut con: Connection = redis_client.get_async_connection().await?;
+        let reply: StreamRangeReply = observe(
+            "XREVRANGE",
+            con.xrevrange_count(PLANET_EVENTS_STREAM_NAME, "+", "-", 1),
+        )
+        .await?;
+
+        let mut position = StreamPosition::default();
+        if let Some(stream_id) = reply.ids.first() {
+            position.advance(&stream_id.id);
+        }
+        Ok(position)
+    }
+
+    /// Moves the position to the entry; returns `false` if it has already been handled
+    pub fn advance(&mut self, stream_id: &str) -> bool {
+        match parse_stream_id(stream_id) {
+            Some(id) if self.last_id < Some(id) => {
+                self.last_id = Some(id);
+                true
+            }
+            Some(_) => false,
+            // entries with unknown IDs can't be ordered, so they are handled
+            None => true,
+        }
+    }
+
+    // `(` makes the start of the range exclusive
+    fn range_start(&self) -> String {
+        match self.last_id {
+            Some((millis, sequence)) => format!("({}-{}", millis, sequence),
+            None => String::from("-"),
+        }
+    }
+}
+
+// an ID consists of Redis time in milliseconds and a sequence number within the millisecond
+fn parse_stream_id(stream_id: &str) -> Option<(u64, u64)> {
+    let (millis, sequence) = stream_id.split_once('-')?;
+    Some((millis.parse().ok()?, sequence.parse().ok()?))
+}
+
+/// Returns the ID of the stream entry that is added to a message when it is published
+pub fn stream_id_of(payload: &str) -> Option<String> {
+    serde_json::from_str::<PlanetMessage>(payload)
+        .ok()
+        .and_then(|message| message.stream_id)
+}
+
+/// Returns IDs and payloads of the last planet events from the stream, oldest first
+pub async fn get_last_planet_events(
+    redis_client: &Client,
+    count: usize,
+) -> Result<Vec<(String, String)>, CustomError> {
+    let mut con: Connection = redis_client.get_async_connection().await?;
+
+    let reply: StreamRangeReply = observe(
+        "XREVRANGE",
+        con.xrevrange_count(PLANET_EVENTS_STREAM_NAME, "+", "-", count),
+    )
+    .await?;
+
+    Ok(reply
+        .ids
+        .iter()
+        .rev()
+        .filter_map(|stream_id| {
+            let payload = stream_id.get::<String>(PLANET_EVENT_PAYLOAD_FIELD)?;
+            Some((stream_id.id.clone(), payload))
+        })
+        .collect())
+}
+
+/// Returns `false` if the event with the given id has already been published
+pub async fn publish_planet_event<C>(
+    con: &mut C,
+    event_id: &str,
+    payload: &str,
+) -> Result<bool, CustomError>
+where
+    C: ConnectionLike,
+{
+    let is_published: bool = observe(
+        "EVALSHA",
+        Script::new(PUBLISH_PLANET_EVENT_SCRIPT)
+            .key(format!("{}:{}", PUBLISHED_EVENT_KEY_PREFIX, event_id))
+            .key(PLANET_EVENTS_STREAM_NAME)
+            .arg(PUBLISHED_EVENT_TTL_SECONDS)
+            .arg(PLANET_EVENTS_STREAM_MAX_LEN)
+            .arg(PLANET_EVENT_PAYLOAD_FIELD)
+            .arg(payload)
+            .arg(NEW_PLANETS_CHANNEL_NAME)
+            .arg(PLANET_EVENT_STREAM_ID_FIELD)
+            .invoke_async(con),
+    )
+    .await?;
+
+    if is_published {
+        crate::metrics::REDIS_PUBSUB_MESSAGES_TOTAL
+            .with_label_values(&["published"])
+            .inc();
+    }
+
+    Ok(is_published)
+}
+
+/// Observes the duration of a command; the timer is stopped when the future completes
+async fn observe<F: Future>(command: &str, future: F) -> F::Output {
+    let _timer = crate::metrics::REDIS_COMMAND_DURATION_SECONDS
+        .with_label_values(&[command])
+        .start_timer();
+    future.await
+}
+
+fn broadcast_planet_event(broadcaster: &Data<Mutex<Broadcaster>>, payload: &str) {
+    crate::metrics::REDIS_PUBSUB_MESSAGES_TOTAL
+        .with_label_values(&["received"])
+        .inc();
+    broadcaster
+        .lock()
+        .expect("Can't lock broadcaster")
+        .send_planet_created(payload);
+}
+
+#[async_trait]
+impl Cache for ConnectionManager {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CustomError> {
+        // `AsyncCommands::get` would be shadowed by this method
+        Ok(observe(
+            "GET",
+            redis::cmd("GET").arg(key).query_async(&mut self.clone()),
+        )
+        .await?)
+    }
+
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, CustomError> {
+        // MGET is used explicitly since it returns a value instead of a list for a single key otherwise
+        Ok(observe(
+            "MGET",
+            redis::cmd("MGET").arg(keys).query_async(&mut self.clone()),
+        )
+        .await?)
+    }
+
+    async fn set_many(
+        &self,
+        entries: Vec<(String, Vec<u8>)>,
+        ttl: Duration,
+    ) -> Result<(), CustomError> {
+        let mut pipe = redis::pipe();
+        for (key, value) in entries.iter() {
+            pipe.set(key, value)
+                .ignore()
+                .expire(key, ttl.as_secs() as usize)
+                .ignore();
+        }
+        let _: () = observe("SET", pipe.atomic().query_async(&mut self.clone())).await?;
+
+        Ok(())
+    }
+
+    async fn delete(&self, keys: &[String]) -> Result<(), CustomError> {
+        let _: () = observe("DEL", self.clone().del(keys)).await?;
+        Ok(())
+    }
+
+    // SCAN is used instead of KEYS, which blocks Redis until all keys are checked
+    async fn delete_by_prefix(&self, prefix: &str) -> Result<u64, CustomError> {
+        let mut con = self.clone();
+        let pattern = format!("{}*", escape_glob(prefix));
+        let mut cursor: u64 = 0;
+        let mut deleted = 0;
+
+        loop {
+            let (next_cursor, keys): (u64, Vec<String>) = observe(
+                "SCAN",
+                redis::cmd("SCAN")
+                    .arg(cursor)
+                    .arg("MATCH")
+                    .arg(&pattern)
+                    .arg("COUNT")
+                    .arg(SCAN_BATCH_SIZE)
+                    .query_async(&mut con),
+            )
+            .await?;
+
+            if !keys.is_empty() {
+                let count: u64 = observe("DEL", con.del(&keys)).await?;
+                deleted += count;
+            }
+            if next_cursor == 0 {
+                return Ok(deleted);
+            }
+            cursor = next_cursor;
+        }
+    }
+
+    async fn ping(&self) -> Result<(), CustomError> {
+        let _: String = observe("PING", redis::cmd("PING").query_async(&mut self.clone())).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl EventPublisher for ConnectionManager {
+    async fn publish_planet_event(
+        &self,
+        event_id: &str,
+        payload: &str,
+    ) -> Result<bool, CustomError> {
+        publish_planet_event(&mut self.clone(), event_id, payload).await
+    }
+}
+
+#[async_trait]
+impl RateLimiterStore for ConnectionManager {
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, CustomError> {
+        let (count, _): (u64, u64) = observe(
+            "INCR",
+            redis::pipe()
+                .atomic()
+                .incr(key, 1)
+                .expire(key, ttl.as_secs() as usize)
+                .query_async(&mut self.clone()),
+        )
+        .await?;
+
+        Ok(count)
+    }
+
+    async fn get(&self, key: &str) -> Result<u64, CustomError> {
+        let count: Option<u64> = observe(
+            "GET",
+            redis::cmd("GET").arg(key).query_async(&mut self.clone()),
+        )
+        .await?;
+
+        Ok(count.unwrap_or(0))
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), CustomError> {
+        let _: () = observe("DEL", self.clone().del(key)).await?;
+        Ok(())
+    }
+}
+
+// special characters of SCAN patterns are matched literally
+fn escape_glob(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
     }
+    escaped
 }
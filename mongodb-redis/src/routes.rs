@@ -0,0 +1,150 @@
+use actix_web::dev::Service;
+use actix_web::http::header::{self, HeaderName, HeaderValue};
+use actix_web::web;
+
+use crate::handlers;
+
+const BULK_PAYLOAD_LIMIT: usize = 16 * 1024 * 1024;
+
+/// Registers all routes; writing routes are registered only if they are enabled, admin routes
+/// aren't registered if they are served on a separate address
+pub fn configure(
+    cfg: &mut web::ServiceConfig,
+    enable_writing_handlers: bool,
+    enable_admin_routes: bool,
+) {
+    if enable_admin_routes {
+        configure_admin(cfg);
+    }
+
+    cfg.service(web::scope("/v1").configure(|cfg| configure_v1(cfg, enable_writing_handlers)))
+        .service(web::scope("/v2").configure(|cfg| configure_v2(cfg, enable_writing_handlers)))
+        .route("/events", web::get().to(handlers::sse))
+        .route("/graphql", web::post().to(handlers::graphql))
+        .route("/graphql", web::get().to(handlers::graphiql))
+        .route("/", web::get().to(handlers::index))
+        .route("/openapi.json", web::get().to(handlers::openapi))
+        .route("/openapi", web::get().to(handlers::openapi_ui))
+        .route("/health/live", web::get().to(handlers::live))
+        .route("/health/ready", web::get().to(handlers::ready))
+        // unversioned paths are served by v1 handlers until they are removed; the scope matches
+        // any path, so it should be registered last
+        .service(
+            web::scope("")
+                .wrap_fn(|req, srv| {
+                    let is_registered_resource = req.resource_map().has_resource(req.path());
+                    let successor_version =
+                        format!("</v1{}>; rel=\"successor-version\"", req.path());
+                    let fut = srv.call(req);
+
+                    async move {
+                        let mut res = fut.await?;
+                        if is_registered_resource {
+                            let headers = res.headers_mut();
+                            headers.insert(
+                                HeaderName::from_static("deprecation"),
+                                HeaderValue::from_static("true"),
+                            );
+                            if let Ok(link) = HeaderValue::from_str(&successor_version) {
+                                headers.insert(header::LINK, link);
+                            }
+                        }
+                        Ok(res)
+                    }
+                })
+                .configure(|cfg| configure_v1(cfg, enable_writing_handlers)),
+        );
+}
+
+/// Admin routes require `AdminConfig` in app data
+pub fn configure_admin(cfg: &mut web::ServiceConfig) {
+    cfg.route("/metrics", web::get().to(handlers::metrics))
+        .service(
+            web::scope("/admin")
+                .route("/cache", web::delete().to(handlers::flush_cache))
+                .route(
+                    "/cache/planets/{planet_id}",
+                    web::delete().to(handlers::invalidate_planet_cache),
+                )
+                .route(
+                    "/rate-limits/{client}",
+                    web::get().to(handlers::get_rate_limit),
+                )
+                .route(
+                    "/rate-limits/{client}",
+                    web::delete().to(handlers::reset_rate_limit),
+                )
+                .route("/sse-clients", web::get().to(handlers::get_sse_clients)),
+        );
+}
+
+fn configure_v1(cfg: &mut web::ServiceConfig, enable_writing_handlers: bool) {
+    cfg.route("/planets", web::get().to(handlers::get_planets))
+        // should be registered before "/planets/{planet_id}" to not be matched by it
+        .route("/planets/export", web::get().to(handlers::export_planets))
+        .route("/planets/stats", web::get().to(handlers::get_planet_stats))
+        .route("/planets/compare", web::get().to(handlers::compare_planets))
+        .route("/planets/{planet_id}", web::get().to(handlers::get_planet))
+        .route(
+            "/planets/{planet_id}/image",
+            web::get().to(handlers::get_image_of_planet),
+        )
+        .route(
+            "/planets/{planet_id}/history",
+            web::get().to(handlers::get_planet_history),
+        );
+
+    if enable_writing_handlers {
+        cfg.route("/planets", web::post().to(handlers::create_planet))
+            .service(
+                web::resource("/planets:bulk")
+                    .app_data(web::PayloadConfig::new(BULK_PAYLOAD_LIMIT))
+                    .route(web::post().to(handlers::bulk_upsert_planets)),
+            )
+            .route(
+                "/planets/{planet_id}",
+                web::put().to(handlers::update_planet),
+            )
+            .route(
+                "/planets/{planet_id}",
+                web::delete().to(handlers::delete_planet),
+            )
+            .route(
+                "/planets/{planet_id}/restore",
+                web::post().to(handlers::restore_planet),
+            )
+            .route(
+                "/planets/{planet_id}/revert/{revision}",
+                web::post().to(handlers::revert_planet),
+            );
+    }
+}
+
+/// Only planets are available in v2 yet, other resources are served by v1
+fn configure_v2(cfg: &mut web::ServiceConfig, enable_writing_handlers: bool) {
+    cfg.route("/planets", web::get().to(handlers::get_planets_v2))
+        .route(
+            "/planets/{planet_id}",
+            web::get().to(handlers::get_planet_v2),
+        )
+        .route(
+            "/planets/{planet_id}/image",
+            web::get().to(handlers::get_image_of_planet),
+        )
+        .route(
+            "/planets/{planet_id}/history",
+            web::get().to(handlers::get_planet_history_v2),
+        );
+
+    if enable_writing_handlers {
+        cfg.route("/planets", web::post().to(handlers::create_planet_v2))
+            .route(
+                "/planets/{planet_id}",
+                web::put().to(handlers::update_planet_v2),
+            )
+            .route(
+                "/planets/{planet_id}",
+                web::delete().to(handlers::delete_planet),
+            );
+    }
+}
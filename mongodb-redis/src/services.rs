@@ -1,160 +1,536 @@
+use std::collections::HashMap;
+use std::future::Future;
 use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use chrono::{Timelike, Utc};
-use log::debug;
+use futures::future;
 use mongodb::bson::oid::ObjectId;
-use redis::aio::ConnectionManager;
-use redis::{AsyncCommands, Client, Value};
+use tokio::task::JoinHandle;
+use tokio::time;
+use tracing::{debug, error, info_span, Instrument, Span};
 
-use crate::db::MongoDbClient;
+use crate::config::EventsConfig;
 use crate::dto::PlanetMessage;
 use crate::errors::CustomError;
-use crate::errors::CustomError::{RedisError, TooManyRequests};
-use crate::model::{Planet, PlanetType};
+use crate::errors::CustomError::{BadRequest, NotFound, TooManyRequests};
+use crate::model::{DependencyHealth, OutboxEvent, Planet, PlanetStats, PlanetType, Revision};
+use crate::storage::{Cache, EventPublisher, PlanetRepository, PlanetStream, RateLimiterStore};
+use crate::telemetry;
 
 const PLANET_KEY_PREFIX: &str = "planet";
 const IMAGE_KEY_PREFIX: &str = "image";
 const RATE_LIMIT_KEY_PREFIX: &str = "rate_limit";
-const MAX_REQUESTS_PER_MINUTE: u64 = 10;
+const STATS_CACHE_KEY: &str = "planet_stats";
+const DEFAULT_REFERENCE_PLANET_NAME: &str = "Earth";
 pub const NEW_PLANETS_CHANNEL_NAME: &str = "new_planets";
+pub const PLANET_EVENTS_STREAM_NAME: &str = "planet_events";
+
+// values of `cache` label of cache metrics
+const PLANET_CACHE_NAME: &str = "planet";
+const IMAGE_CACHE_NAME: &str = "image";
+const CACHE_TTL: Duration = Duration::from_secs(60);
+const RATE_LIMIT_TTL: Duration = Duration::from_secs(60);
+const READINESS_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Clone)]
 pub struct PlanetService {
-    mongodb_client: MongoDbClient,
-    redis_client: Client,
-    redis_connection_manager: ConnectionManager,
+    planet_repository: Arc<dyn PlanetRepository>,
+    cache: Arc<dyn Cache>,
+    event_publisher: Arc<dyn EventPublisher>,
+    event_publishing: EventPublishing,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum EventPublishing {
+    /// An event is published right after a planet is saved
+    Direct,
+    /// An event is saved in the same transaction as a planet and published by the outbox relay
+    Outbox,
+    /// Events are produced by MongoDB change stream
+    Disabled,
+}
+
+impl From<&EventsConfig> for EventPublishing {
+    fn from(config: &EventsConfig) -> Self {
+        if config.enable_change_stream {
+            EventPublishing::Disabled
+        } else if config.enable_outbox {
+            EventPublishing::Outbox
+        } else {
+            EventPublishing::Direct
+        }
+    }
 }
 
 impl PlanetService {
     pub fn new(
-        mongodb_client: MongoDbClient,
-        redis_client: Client,
-        redis_connection_manager: ConnectionManager,
+        planet_repository: Arc<dyn PlanetRepository>,
+        cache: Arc<dyn Cache>,
+        event_publisher: Arc<dyn EventPublisher>,
+        event_publishing: EventPublishing,
     ) -> Self {
         PlanetService {
-            mongodb_client,
-            redis_client,
-            redis_connection_manager,
+            planet_repository,
+            cache,
+            event_publisher,
+            event_publishing,
         }
     }
 
     pub async fn get_planets(
         &self,
         planet_type: Option<PlanetType>,
+        include_deleted: bool,
     ) -> Result<Vec<Planet>, CustomError> {
-        self.mongodb_client.get_planets(planet_type).await
+        self.planet_repository
+            .get_planets(planet_type, include_deleted)
+            .await
     }
 
-    pub async fn create_planet(&self, planet: Planet) -> Result<Planet, CustomError> {
-        let planet = self.mongodb_client.create_planet(planet).await?;
-        self.redis_connection_manager
-            .clone()
-            .publish(
-                NEW_PLANETS_CHANNEL_NAME,
-                serde_json::to_string(&PlanetMessage::from(&planet))?,
-            )
-            .await?;
-        Ok(planet)
+    pub async fn create_planet(&self, planet: Planet, actor: &str) -> Result<Planet, CustomError> {
+        let created_planet = self.save_new_planet(planet, actor).await?;
+        self.invalidate_stats_cache().await?;
+        Ok(created_planet)
+    }
+
+    async fn save_new_planet(&self, planet: Planet, actor: &str) -> Result<Planet, CustomError> {
+        match self.event_publishing {
+            EventPublishing::Direct => {
+                let planet = self.planet_repository.create_planet(planet, actor).await?;
+                self.publish_planet_event(&planet).await;
+                Ok(planet)
+            }
+            EventPublishing::Outbox => {
+                let mut planet = planet;
+                planet.id.get_or_insert_with(ObjectId::new);
+                let message = planet_message(&planet)?;
+                self.planet_repository
+                    .create_planet_with_event(planet, OutboxEvent::new(message), actor)
+                    .await
+            }
+            EventPublishing::Disabled => self.planet_repository.create_planet(planet, actor).await,
+        }
+    }
+
+    // the planet is already saved, so a lost event isn't reported as a failure of the request;
+    // the outbox mode should be used if events must not be lost
+    async fn publish_planet_event(&self, planet: &Planet) {
+        let published = match planet_message(planet) {
+            Ok(message) => {
+                self.event_publisher
+                    .publish_planet_event(&ObjectId::new().to_string(), &message)
+                    .instrument(redis_span("EVALSHA"))
+                    .await
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = published {
+            error!("Can't publish an event about planet {}: {}", planet.name, e);
+        }
     }
 
     pub async fn get_planet(&self, planet_id: &str) -> Result<Planet, CustomError> {
         let cache_key = self.get_planet_cache_key(planet_id);
-        let mut con = self.redis_client.get_async_connection().await?;
 
-        let cached_planet = con.get(&cache_key).await?;
+        let cached_planet = self
+            .cache
+            .get(&cache_key)
+            .instrument(redis_span("GET"))
+            .await?;
         match cached_planet {
-            Value::Nil => {
+            None => {
                 debug!("Use database to retrieve a planet by id: {}", &planet_id);
+                observe_cache_miss(PLANET_CACHE_NAME);
                 let result: Planet = self
-                    .mongodb_client
-                    .get_planet(ObjectId::from_str(planet_id)?)
+                    .planet_repository
+                    .get_planet(ObjectId::from_str(planet_id)?, false)
                     .await?;
 
-                let _: () = redis::pipe()
-                    .atomic()
-                    .set(&cache_key, &result)
-                    .expire(&cache_key, 60)
-                    .query_async(&mut con)
+                self.cache
+                    .set(&cache_key, serde_json::to_vec(&result)?, CACHE_TTL)
+                    .instrument(redis_span("SET"))
                     .await?;
 
                 Ok(result)
             }
-            Value::Data(val) => {
+            Some(val) => {
                 debug!("Use cache to retrieve a planet by id: {}", planet_id);
+                observe_cache_hit(PLANET_CACHE_NAME);
                 Ok(serde_json::from_slice(&val)?)
             }
-            _ => Err(RedisError {
-                message: "Unexpected response from Redis".to_string(),
-            }),
         }
     }
 
+    /// Planets that aren't cached are fetched from the database in one query and cached;
+    /// the result doesn't contain planets that aren't found
+    pub async fn get_planets_by_ids(
+        &self,
+        planet_ids: &[String],
+    ) -> Result<HashMap<String, Planet>, CustomError> {
+        let cache_keys: Vec<String> = planet_ids
+            .iter()
+            .map(|planet_id| self.get_planet_cache_key(planet_id))
+            .collect();
+
+        let cached_planets = self
+            .cache
+            .get_many(&cache_keys)
+            .instrument(redis_span("MGET"))
+            .await?;
+
+        let mut result = HashMap::new();
+        let mut missed_ids = Vec::new();
+        for (planet_id, cached_planet) in planet_ids.iter().zip(cached_planets) {
+            match cached_planet {
+                Some(planet) => {
+                    observe_cache_hit(PLANET_CACHE_NAME);
+                    result.insert(planet_id.clone(), serde_json::from_slice(&planet)?);
+                }
+                // an invalid id can't match any planet
+                None => {
+                    observe_cache_miss(PLANET_CACHE_NAME);
+                    missed_ids.extend(ObjectId::from_str(planet_id).ok());
+                }
+            }
+        }
+
+        if !missed_ids.is_empty() {
+            debug!("Use database to retrieve planets by ids: {:?}", missed_ids);
+            let planets = self
+                .planet_repository
+                .get_planets_by_ids(missed_ids)
+                .await?;
+
+            let mut entries = Vec::with_capacity(planets.len());
+            for planet in planets {
+                let planet_id = planet.id.expect("Planet.id is not specified").to_string();
+                entries.push((
+                    self.get_planet_cache_key(&planet_id),
+                    serde_json::to_vec(&planet)?,
+                ));
+                result.insert(planet_id, planet);
+            }
+            self.cache
+                .set_many(entries, CACHE_TTL)
+                .instrument(redis_span("SET"))
+                .await?;
+        }
+
+        Ok(result)
+    }
+
+    pub async fn get_planets_page(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+        skip: u64,
+        limit: i64,
+    ) -> Result<Vec<Planet>, CustomError> {
+        self.planet_repository
+            .get_planets_page(planet_type, include_deleted, skip, limit)
+            .await
+    }
+
+    pub async fn get_planets_stream(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+    ) -> Result<PlanetStream, CustomError> {
+        self.planet_repository
+            .get_planets_stream(planet_type, include_deleted)
+            .await
+    }
+
+    /// Updates a planet with the same name if it exists, otherwise creates a new one;
+    /// the flag in the result shows whether a planet was created
+    pub async fn upsert_planet_by_name(
+        &self,
+        planet: Planet,
+        actor: &str,
+    ) -> Result<(Planet, bool), CustomError> {
+        let mut planet = planet;
+        let event = match self.event_publishing {
+            EventPublishing::Outbox => {
+                planet.id.get_or_insert_with(ObjectId::new);
+                Some(OutboxEvent::new(planet_message(&planet)?))
+            }
+            EventPublishing::Direct | EventPublishing::Disabled => None,
+        };
+
+        let (planet, created) = self
+            .planet_repository
+            .upsert_planet_by_name(planet, event, actor)
+            .await?;
+
+        if created {
+            if self.event_publishing == EventPublishing::Direct {
+                self.publish_planet_event(&planet).await;
+            }
+            self.invalidate_stats_cache().await?;
+        } else {
+            let planet_id = planet.id.expect("Planet.id is not specified").to_string();
+            self.invalidate_planet_cache(&planet_id).await?;
+        }
+
+        Ok((planet, created))
+    }
+
+    /// Returns the reference planet and the compared ones; Earth is the reference by default
+    pub async fn compare_planets(
+        &self,
+        planet_ids: &[String],
+        reference_planet_id: Option<&str>,
+    ) -> Result<(Planet, Vec<Planet>), CustomError> {
+        let reference_planet = async {
+            match reference_planet_id {
+                Some(reference_planet_id) => self.get_planet(reference_planet_id).await,
+                None => {
+                    let planet_id = self
+                        .planet_repository
+                        .find_planet_by_name(DEFAULT_REFERENCE_PLANET_NAME)
+                        .await?
+                        .and_then(|planet| planet.id)
+                        .ok_or(NotFound {
+                            message: format!(
+                                "Can't find a reference planet: {}",
+                                DEFAULT_REFERENCE_PLANET_NAME
+                            ),
+                        })?;
+                    self.get_planet(&planet_id.to_string()).await
+                }
+            }
+        };
+        let planets = future::try_join_all(
+            planet_ids
+                .iter()
+                .map(|planet_id| self.get_planet(planet_id)),
+        );
+
+        future::try_join(reference_planet, planets).await
+    }
+
+    /// Deleted planets aren't cached, so the database is always used
+    pub async fn get_planet_including_deleted(
+        &self,
+        planet_id: &str,
+    ) -> Result<Planet, CustomError> {
+        self.planet_repository
+            .get_planet(ObjectId::from_str(planet_id)?, true)
+            .await
+    }
+
     pub async fn update_planet(
         &self,
         planet_id: &str,
         planet: Planet,
+        actor: &str,
     ) -> Result<Planet, CustomError> {
         let updated_planet = self
-            .mongodb_client
-            .update_planet(ObjectId::from_str(planet_id)?, planet)
+            .planet_repository
+            .update_planet(ObjectId::from_str(planet_id)?, planet, actor)
             .await?;
 
-        let cache_key = self.get_planet_cache_key(planet_id);
-        self.redis_connection_manager.clone().del(cache_key).await?;
+        self.invalidate_planet_cache(planet_id).await?;
 
         Ok(updated_planet)
     }
 
-    pub async fn delete_planet(&self, planet_id: &str) -> Result<(), CustomError> {
-        self.mongodb_client
-            .delete_planet(ObjectId::from_str(planet_id)?)
+    pub async fn delete_planet(&self, planet_id: &str, actor: &str) -> Result<(), CustomError> {
+        self.planet_repository
+            .delete_planet(ObjectId::from_str(planet_id)?, actor)
             .await?;
 
-        let cache_key = self.get_planet_cache_key(planet_id);
-        self.redis_connection_manager.clone().del(cache_key).await?;
+        self.invalidate_planet_cache(planet_id).await?;
 
         Ok(())
     }
 
+    pub async fn restore_planet(
+        &self,
+        planet_id: &str,
+        actor: &str,
+    ) -> Result<Planet, CustomError> {
+        let restored_planet = self
+            .planet_repository
+            .restore_planet(ObjectId::from_str(planet_id)?, actor)
+            .await?;
+
+        self.invalidate_planet_cache(planet_id).await?;
+
+        Ok(restored_planet)
+    }
+
+    pub async fn get_planet_stats(&self) -> Result<PlanetStats, CustomError> {
+        let cached_stats = self
+            .cache
+            .get(STATS_CACHE_KEY)
+            .instrument(redis_span("GET"))
+            .await?;
+        match cached_stats {
+            None => {
+                debug!("Use database to calculate planet statistics");
+                let result = self.planet_repository.get_planet_stats().await?;
+
+                self.cache
+                    .set(STATS_CACHE_KEY, serde_json::to_vec(&result)?, CACHE_TTL)
+                    .instrument(redis_span("SET"))
+                    .await?;
+
+                Ok(result)
+            }
+            Some(val) => {
+                debug!("Use cache to retrieve planet statistics");
+                Ok(serde_json::from_slice(&val)?)
+            }
+        }
+    }
+
+    /// Caches all planets and their statistics even if they are already cached, e.g. to refresh
+    /// their TTL before a load peak. Returns the number of cached planets
+    pub async fn warm_cache(&self) -> Result<usize, CustomError> {
+        let planets = self.planet_repository.get_planets(None, false).await?;
+        let stats = self.planet_repository.get_planet_stats().await?;
+
+        let mut entries = Vec::with_capacity(planets.len() + 1);
+        for planet in planets.iter() {
+            let planet_id = planet.id.expect("Planet.id is not specified").to_string();
+            entries.push((
+                self.get_planet_cache_key(&planet_id),
+                serde_json::to_vec(planet)?,
+            ));
+        }
+        entries.push((STATS_CACHE_KEY.to_string(), serde_json::to_vec(&stats)?));
+
+        self.cache
+            .set_many(entries, CACHE_TTL)
+            .instrument(redis_span("SET"))
+            .await?;
+
+        Ok(planets.len())
+    }
+
+    pub async fn get_planet_history(&self, planet_id: &str) -> Result<Vec<Revision>, CustomError> {
+        let revisions = self
+            .planet_repository
+            .get_revisions(ObjectId::from_str(planet_id)?)
+            .await?;
+
+        if revisions.is_empty() {
+            return Err(NotFound {
+                message: format!("Can't find history of a planet by id: {}", planet_id),
+            });
+        }
+
+        Ok(revisions)
+    }
+
+    pub async fn revert_planet(
+        &self,
+        planet_id: &str,
+        revision: i64,
+        actor: &str,
+    ) -> Result<Planet, CustomError> {
+        let reverted_planet = self
+            .planet_repository
+            .revert_planet(ObjectId::from_str(planet_id)?, revision, actor)
+            .await?;
+
+        self.invalidate_planet_cache(planet_id).await?;
+
+        Ok(reverted_planet)
+    }
+
     pub async fn get_image_of_planet(&self, planet_id: &str) -> Result<Vec<u8>, CustomError> {
         let cache_key = self.get_image_cache_key(planet_id);
-        let mut redis_connection_manager = self.redis_connection_manager.clone();
 
-        let cached_image = redis_connection_manager.get(&cache_key).await?;
+        let cached_image = self
+            .cache
+            .get(&cache_key)
+            .instrument(redis_span("GET"))
+            .await?;
         match cached_image {
-            Value::Nil => {
+            None => {
                 debug!(
                     "Use database to retrieve an image of a planet by id: {}",
                     &planet_id
                 );
+                observe_cache_miss(IMAGE_CACHE_NAME);
                 let planet = self
-                    .mongodb_client
-                    .get_planet(ObjectId::from_str(planet_id)?)
+                    .planet_repository
+                    .get_planet(ObjectId::from_str(planet_id)?, false)
                     .await?;
                 let result = crate::db::get_image_of_planet(&planet.name).await;
 
-                let _: () = redis::pipe()
-                    .atomic()
-                    .set(&cache_key, result.clone())
-                    .expire(&cache_key, 60)
-                    .query_async(&mut redis_connection_manager)
+                self.cache
+                    .set(&cache_key, result.clone(), CACHE_TTL)
+                    .instrument(redis_span("SET"))
                     .await?;
 
                 Ok(result)
             }
-            Value::Data(val) => {
+            Some(val) => {
                 debug!(
                     "Use cache to retrieve an image of a planet by id: {}",
                     &planet_id
                 );
+                observe_cache_hit(IMAGE_CACHE_NAME);
                 Ok(val)
             }
-            _ => Err(RedisError {
-                message: "Unexpected response from Redis".to_string(),
-            }),
         }
     }
 
+    pub async fn invalidate_planet_cache(&self, planet_id: &str) -> Result<(), CustomError> {
+        self.cache
+            .delete(&[
+                self.get_planet_cache_key(planet_id),
+                self.get_image_cache_key(planet_id),
+                STATS_CACHE_KEY.to_string(),
+            ])
+            .instrument(redis_span("DEL"))
+            .await
+    }
+
+    /// Deletes cached planets and their images if the prefix starts with `planet:` (e.g.
+    /// `planet:` or `planet:{id}`) or cached stats if it is `planet_stats`; both are deleted if
+    /// there is no prefix. Returns the number of deleted entries
+    pub async fn flush_cache(&self, prefix: Option<&str>) -> Result<u64, CustomError> {
+        let planet_key_prefix = format!("{}:", PLANET_KEY_PREFIX);
+        // other keys, e.g. of rate limits or planet events, can't be deleted this way
+        let prefixes = match prefix {
+            None => vec![planet_key_prefix.as_str(), STATS_CACHE_KEY],
+            Some(prefix) if prefix.starts_with(&planet_key_prefix) || prefix == STATS_CACHE_KEY => {
+                vec![prefix]
+            }
+            Some(prefix) => {
+                return Err(BadRequest {
+                    message: format!(
+                        "Cache prefix {} is not supported; it should start with {} or be {}",
+                        prefix, planet_key_prefix, STATS_CACHE_KEY
+                    ),
+                })
+            }
+        };
+
+        let mut deleted = 0;
+        for prefix in prefixes {
+            deleted += self
+                .cache
+                .delete_by_prefix(prefix)
+                .instrument(redis_span("SCAN"))
+                .await?;
+        }
+        Ok(deleted)
+    }
+
+    pub async fn invalidate_stats_cache(&self) -> Result<(), CustomError> {
+        self.cache
+            .delete(&[STATS_CACHE_KEY.to_string()])
+            .instrument(redis_span("DEL"))
+            .await
+    }
+
     fn get_planet_cache_key(&self, planet_id: &str) -> String {
         format!("{}:{}", PLANET_KEY_PREFIX, planet_id)
     }
@@ -166,34 +542,170 @@ impl PlanetService {
 
 #[derive(Clone)]
 pub struct RateLimitingService {
-    redis_connection_manager: ConnectionManager,
+    rate_limiter_store: Arc<dyn RateLimiterStore>,
+    max_requests_per_minute: u64,
 }
 
 impl RateLimitingService {
-    pub fn new(redis_connection_manager: ConnectionManager) -> Self {
+    pub fn new(
+        rate_limiter_store: Arc<dyn RateLimiterStore>,
+        max_requests_per_minute: u64,
+    ) -> Self {
         RateLimitingService {
-            redis_connection_manager,
+            rate_limiter_store,
+            max_requests_per_minute,
         }
     }
 
     pub async fn assert_rate_limit_not_exceeded(&self, ip_addr: String) -> Result<(), CustomError> {
-        let current_minute = Utc::now().minute();
-        let rate_limit_key = format!("{}:{}:{}", RATE_LIMIT_KEY_PREFIX, ip_addr, current_minute);
-
-        let (count, _): (u64, u64) = redis::pipe()
-            .atomic()
-            .incr(&rate_limit_key, 1)
-            .expire(&rate_limit_key, 60)
-            .query_async(&mut self.redis_connection_manager.clone())
+        let count = self
+            .rate_limiter_store
+            .increment(&self.get_rate_limit_key(&ip_addr), RATE_LIMIT_TTL)
+            .instrument(redis_span("INCR"))
             .await?;
 
-        if count > MAX_REQUESTS_PER_MINUTE {
+        if count > self.max_requests_per_minute {
+            crate::metrics::RATE_LIMIT_REJECTIONS_TOTAL.inc();
             Err(TooManyRequests {
                 actual_count: count,
-                permitted_count: MAX_REQUESTS_PER_MINUTE,
+                permitted_count: self.max_requests_per_minute,
             })
         } else {
             Ok(())
         }
     }
+
+    /// Returns the number of requests made by the client in the current minute
+    pub async fn get_request_count(&self, ip_addr: &str) -> Result<u64, CustomError> {
+        self.rate_limiter_store
+            .get(&self.get_rate_limit_key(ip_addr))
+            .instrument(redis_span("GET"))
+            .await
+    }
+
+    pub async fn reset(&self, ip_addr: &str) -> Result<(), CustomError> {
+        self.rate_limiter_store
+            .delete(&self.get_rate_limit_key(ip_addr))
+            .instrument(redis_span("DEL"))
+            .await
+    }
+
+    pub fn max_requests_per_minute(&self) -> u64 {
+        self.max_requests_per_minute
+    }
+
+    fn get_rate_limit_key(&self, ip_addr: &str) -> String {
+        format!(
+            "{}:{}:{}",
+            RATE_LIMIT_KEY_PREFIX,
+            ip_addr,
+            Utc::now().minute()
+        )
+    }
+}
+
+pub struct HealthService {
+    planet_repository: Arc<dyn PlanetRepository>,
+    cache: Arc<dyn Cache>,
+    pubsub_listener: Arc<JoinHandle<()>>,
+}
+
+impl HealthService {
+    pub fn new(
+        planet_repository: Arc<dyn PlanetRepository>,
+        cache: Arc<dyn Cache>,
+        pubsub_listener: Arc<JoinHandle<()>>,
+    ) -> Self {
+        HealthService {
+            planet_repository,
+            cache,
+            pubsub_listener,
+        }
+    }
+
+    /// Dependencies are checked concurrently, each within a timeout
+    pub async fn check_readiness(&self) -> Vec<DependencyHealth> {
+        let (mongodb, redis) = future::join(
+            check_dependency("mongodb", self.planet_repository.ping()),
+            check_dependency("redis", self.cache.ping()),
+        )
+        .await;
+
+        let checks = vec![mongodb, redis, self.check_pubsub_listener()];
+        for check in checks.iter() {
+            crate::metrics::READINESS_DEPENDENCY_UP
+                .with_label_values(&[check.dependency])
+                .set(check.error.is_none() as i64);
+            crate::metrics::READINESS_DEPENDENCY_LATENCY_SECONDS
+                .with_label_values(&[check.dependency])
+                .set(check.latency.as_secs_f64());
+        }
+
+        checks
+    }
+
+    fn check_pubsub_listener(&self) -> DependencyHealth {
+        let error = if self.pubsub_listener.is_finished() {
+            Some(String::from("Listener task is finished"))
+        } else if crate::metrics::REDIS_PUBSUB_LISTENER_UP.get() == 0 {
+            Some(String::from("Listener is reconnecting"))
+        } else {
+            None
+        };
+
+        DependencyHealth {
+            dependency: "redis_pubsub_listener",
+            latency: Duration::ZERO,
+            error,
+        }
+    }
+}
+
+// a call to the cache, the rate limiter or the event publisher is done in one Redis command,
+// pipeline or script
+fn redis_span(command: &str) -> Span {
+    info_span!(
+        "redis",
+        otel.name = command,
+        otel.kind = "client",
+        db.system = "redis",
+        db.operation = command,
+    )
+}
+
+// the trace context allows to link delivery of the event to the request that created the planet
+fn planet_message(planet: &Planet) -> Result<String, CustomError> {
+    let mut message = PlanetMessage::from(planet);
+    message.trace_context = telemetry::current_trace_context();
+    Ok(serde_json::to_string(&message)?)
+}
+
+fn observe_cache_hit(cache: &str) {
+    crate::metrics::CACHE_HITS_TOTAL
+        .with_label_values(&[cache])
+        .inc();
+}
+
+fn observe_cache_miss(cache: &str) {
+    crate::metrics::CACHE_MISSES_TOTAL
+        .with_label_values(&[cache])
+        .inc();
+}
+
+async fn check_dependency<F>(dependency: &'static str, check: F) -> DependencyHealth
+where
+    F: Future<Output = Result<(), CustomError>>,
+{
+    let started_at = Instant::now();
+    let error = match time::timeout(READINESS_CHECK_TIMEOUT, check).await {
+        Ok(Ok(())) => None,
+        Ok(Err(e)) => Some(e.to_string()),
+        Err(_) => Some(format!("No response within {:?}", READINESS_CHECK_TIMEOUT)),
+    };
+
+    DependencyHealth {
+        dependency,
+        latency: started_at.elapsed(),
+        error,
+    }
 }
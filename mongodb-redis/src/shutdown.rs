@@ -0,0 +1,146 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::dev::ServerHandle;
+use actix_web::web::Data;
+use futures::future;
+use redis::aio::ConnectionManager;
+use tokio::signal;
+use tokio::signal::unix::{self, SignalKind};
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tracing::{error, info, warn};
+
+use crate::broadcaster::Broadcaster;
+use crate::db::MongoDbClient;
+
+/// Resolves on SIGINT or SIGTERM
+pub async fn wait_for_signal() {
+    let mut sigterm = unix::signal(SignalKind::terminate()).expect("Can't listen to SIGTERM");
+
+    tokio::select! {
+        _ = signal::ctrl_c() => info!("SIGINT is received"),
+        _ = sigterm.recv() => info!("SIGTERM is received"),
+    }
+}
+
+/// Share of the shutdown timeout given to in-flight requests; the rest is left for releasing
+/// resources, so that a lingering connection can't take all of it
+pub fn server_shutdown_timeout(timeout: Duration) -> Duration {
+    timeout / 2
+}
+
+/// HTTP servers of the application; their services hold clones of MongoDB and Redis clients,
+/// which are dropped along with the servers
+pub struct Servers {
+    pub server: ServerHandle,
+    pub server_task: JoinHandle<io::Result<()>>,
+    // is stopped after the outbox is flushed, so that metrics can be scraped meanwhile
+    pub admin_server: Option<(ServerHandle, JoinHandle<io::Result<()>>)>,
+}
+
+/// Connections that are closed after the servers and background tasks which use them are stopped
+pub struct Dependencies {
+    pub mongodb_client: MongoDbClient,
+    pub redis_connection_manager: ConnectionManager,
+    // holds the Redis Pub/Sub connection
+    pub pubsub_listener: Arc<JoinHandle<()>>,
+}
+
+/// Stops the servers and releases resources within the timeout. The server stops accepting
+/// connections before SSE clients are notified, so that they reconnect to another instance, and
+/// the clients are disconnected, otherwise the server would wait for their streams until the
+/// timeout. Every step is limited by its share of the timeout, so connections are closed even if
+/// a previous step takes too long. Returns the result of the main server
+pub async fn shutdown(
+    servers: Servers,
+    broadcaster: Data<Mutex<Broadcaster>>,
+    background_tasks: Vec<JoinHandle<()>>,
+    dependencies: Dependencies,
+    flush_outbox: bool,
+    timeout: Duration,
+) -> io::Result<()> {
+    info!("Shutting down within {:?}", timeout);
+    let deadline = Instant::now() + timeout;
+    let Servers {
+        server,
+        mut server_task,
+        admin_server,
+    } = servers;
+    let Dependencies {
+        mongodb_client,
+        redis_connection_manager,
+        pubsub_listener,
+    } = dependencies;
+
+    server.pause().await;
+    broadcaster
+        .lock()
+        .expect("Can't lock broadcaster")
+        .shutdown();
+
+    // waits for in-flight requests; the server is dropped along with its task
+    let stop = async {
+        server.stop(true).await;
+        (&mut server_task).await
+    };
+    let result = match time::timeout(server_shutdown_timeout(timeout), stop).await {
+        Ok(result) => {
+            info!("HTTP server is stopped");
+            result.expect("HTTP server task failed")
+        }
+        Err(_) => {
+            warn!("HTTP server isn't stopped in time");
+            server_task.abort();
+            Ok(())
+        }
+    };
+
+    // the pub/sub listener is stopped after the server since readiness checks depend on it
+    pubsub_listener.abort();
+    for task in background_tasks.iter() {
+        task.abort();
+    }
+    let stop_tasks = future::join_all(background_tasks);
+    match time::timeout_at(deadline, stop_tasks).await {
+        Ok(_) => info!("Background tasks are stopped"),
+        Err(_) => warn!("Background tasks aren't stopped in time"),
+    }
+
+    // the outbox may take half of the remaining time, the rest is left for closing connections
+    if flush_outbox {
+        let flush_deadline =
+            Instant::now() + deadline.saturating_duration_since(Instant::now()) / 2;
+        let flush = crate::outbox::flush_outbox(&mongodb_client, &redis_connection_manager);
+        match time::timeout_at(flush_deadline, flush).await {
+            Ok(Ok(())) => info!("Outbox is flushed"),
+            Ok(Err(e)) => error!("Can't flush outbox: {}", e),
+            Err(_) => warn!("Outbox isn't flushed in time"),
+        }
+    }
+
+    if let Some((admin_server, admin_server_task)) = admin_server {
+        let stop = async {
+            admin_server.stop(true).await;
+            admin_server_task.await
+        };
+        match time::timeout_at(deadline, stop).await {
+            Ok(_) => info!("Admin server is stopped"),
+            Err(_) => warn!("Admin server isn't stopped in time"),
+        }
+    }
+
+    // connections of the cache, the rate limiter and the outbox are shared by clones of
+    // the manager and closed once the last one is dropped; the Pub/Sub connection is dropped
+    // along with the listener
+    drop(redis_connection_manager);
+    info!("Redis connections are closed");
+
+    match time::timeout_at(deadline, mongodb_client.shutdown()).await {
+        Ok(()) => info!("MongoDB client is closed"),
+        Err(_) => warn!("MongoDB client isn't closed within {:?}", timeout),
+    }
+
+    result
+}
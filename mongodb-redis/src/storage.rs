@@ -0,0 +1,134 @@
+use std::time::Duration;
+
+use async_trait::async_trait;
+use futures::stream::BoxStream;
+use mongodb::bson::oid::ObjectId;
+
+use crate::errors::CustomError;
+use crate::model::{OutboxEvent, Planet, PlanetStats, PlanetType, Revision};
+
+/// Planets are read one by one, so the whole list isn't kept in memory
+pub type PlanetStream = BoxStream<'static, Result<Planet, CustomError>>;
+
+/// Deleted planets are excluded unless `include_deleted` is set; changes are recorded as revisions
+#[async_trait]
+pub trait PlanetRepository: Send + Sync {
+    async fn get_planets(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+    ) -> Result<Vec<Planet>, CustomError>;
+
+    async fn get_planets_stream(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+    ) -> Result<PlanetStream, CustomError>;
+
+    /// Planets are sorted by id
+    async fn get_planets_page(
+        &self,
+        planet_type: Option<PlanetType>,
+        include_deleted: bool,
+        skip: u64,
+        limit: i64,
+    ) -> Result<Vec<Planet>, CustomError>;
+
+    async fn get_planets_by_ids(&self, ids: Vec<ObjectId>) -> Result<Vec<Planet>, CustomError>;
+
+    async fn get_planet_stats(&self) -> Result<PlanetStats, CustomError>;
+
+    async fn find_planet_by_name(&self, name: &str) -> Result<Option<Planet>, CustomError>;
+
+    async fn get_planet(&self, id: ObjectId, include_deleted: bool) -> Result<Planet, CustomError>;
+
+    async fn create_planet(&self, planet: Planet, actor: &str) -> Result<Planet, CustomError>;
+
+    /// Saves a planet and an event about it atomically
+    async fn create_planet_with_event(
+        &self,
+        planet: Planet,
+        event: OutboxEvent,
+        actor: &str,
+    ) -> Result<Planet, CustomError>;
+
+    async fn update_planet(
+        &self,
+        id: ObjectId,
+        planet: Planet,
+        actor: &str,
+    ) -> Result<Planet, CustomError>;
+
+    /// Atomically updates a planet with the same name that isn't deleted or creates a new one;
+    /// the event is saved only if a planet is created. The flag in the result shows whether
+    /// a planet was created
+    async fn upsert_planet_by_name(
+        &self,
+        planet: Planet,
+        event: Option<OutboxEvent>,
+        actor: &str,
+    ) -> Result<(Planet, bool), CustomError>;
+
+    async fn delete_planet(&self, id: ObjectId, actor: &str) -> Result<(), CustomError>;
+
+    async fn restore_planet(&self, id: ObjectId, actor: &str) -> Result<Planet, CustomError>;
+
+    async fn get_revisions(&self, planet_id: ObjectId) -> Result<Vec<Revision>, CustomError>;
+
+    async fn revert_planet(
+        &self,
+        id: ObjectId,
+        revision: i64,
+        actor: &str,
+    ) -> Result<Planet, CustomError>;
+
+    async fn ping(&self) -> Result<(), CustomError>;
+}
+
+/// Entries expire after the specified time-to-live
+#[async_trait]
+pub trait Cache: Send + Sync {
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, CustomError>;
+
+    /// Values are returned in the order of the keys
+    async fn get_many(&self, keys: &[String]) -> Result<Vec<Option<Vec<u8>>>, CustomError>;
+
+    async fn set(&self, key: &str, value: Vec<u8>, ttl: Duration) -> Result<(), CustomError> {
+        self.set_many(vec![(key.to_string(), value)], ttl).await
+    }
+
+    /// Entries are saved atomically
+    async fn set_many(
+        &self,
+        entries: Vec<(String, Vec<u8>)>,
+        ttl: Duration,
+    ) -> Result<(), CustomError>;
+
+    async fn delete(&self, keys: &[String]) -> Result<(), CustomError>;
+
+    /// Returns the number of deleted entries
+    async fn delete_by_prefix(&self, prefix: &str) -> Result<u64, CustomError>;
+
+    async fn ping(&self) -> Result<(), CustomError>;
+}
+
+#[async_trait]
+pub trait EventPublisher: Send + Sync {
+    /// Returns `false` if the event with the given id has already been published
+    async fn publish_planet_event(
+        &self,
+        event_id: &str,
+        payload: &str,
+    ) -> Result<bool, CustomError>;
+}
+
+#[async_trait]
+pub trait RateLimiterStore: Send + Sync {
+    /// Returns the value of the counter after the increment; the counter expires after `ttl`
+    async fn increment(&self, key: &str, ttl: Duration) -> Result<u64, CustomError>;
+
+    /// Returns 0 if the counter doesn't exist or has expired
+    async fn get(&self, key: &str) -> Result<u64, CustomError>;
+
+    async fn delete(&self, key: &str) -> Result<(), CustomError>;
+}
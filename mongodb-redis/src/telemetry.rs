@@ -0,0 +1,241 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::HeaderMap;
+use chrono::{SecondsFormat, Utc};
+use opentelemetry::propagation::Extractor;
+use opentelemetry::trace::{TraceContextExt, TracerProvider as _};
+use opentelemetry::{global, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use opentelemetry_sdk::{runtime, Resource};
+use serde_json::{Map, Value};
+use tracing::field::{Empty, Field, Visit};
+use tracing::level_filters::LevelFilter;
+use tracing::{error, info_span, Event, Span, Subscriber};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::config::TracingConfig;
+use crate::request_id;
+
+/// Log records are filtered by `RUST_LOG` env var and written to stdout as JSON; spans are exported
+/// via OTLP if the endpoint is specified. Returns the provider that flushes spans on shutdown
+pub fn init(config: &TracingConfig) -> Option<TracerProvider> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let tracer_provider = if config.otlp_endpoint.is_empty() {
+        None
+    } else {
+        Some(create_tracer_provider(config))
+    };
+
+    // spans are exported regardless of the log level
+    let otel_layer = tracer_provider.as_ref().map(|tracer_provider| {
+        tracing_opentelemetry::layer()
+            .with_tracer(tracer_provider.tracer(config.service_name.clone()))
+            .with_filter(LevelFilter::INFO)
+    });
+
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .event_format(JsonFormat)
+                .with_filter(EnvFilter::from_default_env()),
+        )
+        .with(otel_layer)
+        .init();
+
+    tracer_provider
+}
+
+fn create_tracer_provider(config: &TracingConfig) -> TracerProvider {
+    let exporter = SpanExporter::builder()
+        .with_tonic()
+        .with_endpoint(config.otlp_endpoint.clone())
+        .build()
+        .expect("Can't create OTLP exporter");
+
+    // the exporter runs on a separate thread since actix runtime is single-threaded
+    TracerProvider::builder()
+        .with_batch_exporter(exporter, runtime::TokioCurrentThread)
+        .with_resource(Resource::new(vec![KeyValue::new(
+            "service.name",
+            config.service_name.clone(),
+        )]))
+        .build()
+}
+
+pub fn shutdown(tracer_provider: Option<TracerProvider>) {
+    if let Some(tracer_provider) = tracer_provider {
+        if let Err(e) = tracer_provider.shutdown() {
+            error!("Can't flush spans: {}", e);
+        }
+    }
+}
+
+/// Continues the trace of the caller if the request has `traceparent` header
+pub fn request_span(req: &ServiceRequest) -> Span {
+    let route = req
+        .match_pattern()
+        .unwrap_or_else(|| String::from("unmatched"));
+
+    let span = info_span!(
+        "HTTP request",
+        otel.name = %format!("{} {}", req.method(), route),
+        otel.kind = "server",
+        otel.status_code = Empty,
+        http.request.method = %req.method(),
+        http.route = %route,
+        url.path = req.path(),
+        http.response.status_code = Empty,
+        request_id = %request_id::current().unwrap_or_default(),
+    );
+    span.set_parent(global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    }));
+    span
+}
+
+pub fn record_response<B>(span: &Span, res: &ServiceResponse<B>) {
+    let status = res.status();
+    span.record("http.response.status_code", status.as_u16());
+    if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+    }
+}
+
+/// Returns `traceparent` and `tracestate` of the current span, e.g. to pass them in a message
+pub fn current_trace_context() -> HashMap<String, String> {
+    let mut trace_context = HashMap::new();
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&Span::current().context(), &mut trace_context)
+    });
+    trace_context
+}
+
+/// Returns id of the trace of the current span if spans are exported and the trace is sampled
+pub fn current_trace_id() -> Option<String> {
+    let span_context = Span::current().context().span().span_context().clone();
+    if span_context.is_sampled() {
+        Some(span_context.trace_id().to_string())
+    } else {
+        None
+    }
+}
+
+/// Makes the span a part of the trace the context of which was passed in a message
+pub fn continue_trace(span: &Span, trace_context: &HashMap<String, String>) {
+    if !trace_context.is_empty() {
+        span.set_parent(global::get_text_map_propagator(|propagator| {
+            propagator.extract(trace_context)
+        }));
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|key| key.as_str()).collect()
+    }
+}
+
+/// Writes a log record as a JSON object with the fields of the event, the id of the request
+/// being handled and the id of the trace if there is any
+struct JsonFormat;
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let metadata = event.metadata();
+        let mut record = Map::new();
+        record.insert(
+            String::from("timestamp"),
+            Value::from(Utc::now().to_rfc3339_opts(SecondsFormat::Micros, true)),
+        );
+        record.insert(
+            String::from("level"),
+            Value::from(metadata.level().as_str()),
+        );
+        record.insert(String::from("target"), Value::from(metadata.target()));
+        if let Some(span) = ctx.lookup_current() {
+            record.insert(String::from("span"), Value::from(span.name()));
+        }
+        if let Some(request_id) = request_id::current() {
+            record.insert(String::from("request_id"), Value::from(request_id));
+        }
+        let span_context = Span::current().context().span().span_context().clone();
+        if span_context.is_valid() {
+            record.insert(
+                String::from("trace_id"),
+                Value::from(span_context.trace_id().to_string()),
+            );
+        }
+        event.record(&mut JsonVisitor(&mut record));
+
+        writeln!(writer, "{}", Value::Object(record))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, Value::from(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, Value::from(format!("{:?}", value)));
+    }
+}
+
+impl JsonVisitor<'_> {
+    // records of `log` crate (e.g. produced by actix-web) carry their target and location in fields
+    fn record(&mut self, field: &Field, value: Value) {
+        match field.name() {
+            "log.target" => {
+                self.0.insert(String::from("target"), value);
+            }
+            name if name.starts_with("log.") => {}
+            name => {
+                self.0.insert(name.to_string(), value);
+            }
+        }
+    }
+}
@@ -0,0 +1,1027 @@
+use std::future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::ServiceResponse;
+use actix_web::http::{header, StatusCode};
+use actix_web::test::{self, TestRequest};
+use actix_web::web::{Bytes, Data};
+use actix_web::App;
+use mongodb::bson::{self, doc, oid::ObjectId, Document};
+use mongodb::change_stream::event::ChangeStreamEvent;
+use opentelemetry::global;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::TracerProvider;
+use serde_json::{json, Value};
+use tracing::{info_span, Instrument};
+use tracing_subscriber::layer::SubscriberExt;
+
+use mongodb_redis::broadcaster::Broadcaster;
+use mongodb_redis::change_stream;
+use mongodb_redis::config::AdminConfig;
+use mongodb_redis::graphql::{self, PlanetSchema};
+use mongodb_redis::http_metrics::HttpMetrics;
+use mongodb_redis::memory::{
+    InMemoryCache, InMemoryEventPublisher, InMemoryPlanetRepository, InMemoryRateLimiterStore,
+};
+use mongodb_redis::request_id::{RequestId, REQUEST_ID_HEADER};
+use mongodb_redis::routes;
+use mongodb_redis::services::{EventPublishing, HealthService, PlanetService, RateLimitingService};
+use mongodb_redis::storage::Cache;
+
+const MAX_REQUESTS_PER_MINUTE: u64 = 100;
+
+struct TestContext {
+    cache: Arc<InMemoryCache>,
+    event_publisher: Arc<InMemoryEventPublisher>,
+    broadcaster: Data<Mutex<Broadcaster>>,
+    planet_service: Data<PlanetService>,
+    rate_limiting_service: Data<RateLimitingService>,
+    health_service: Data<HealthService>,
+    graphql_schema: Data<PlanetSchema>,
+    admin_config: Data<AdminConfig>,
+    enable_writing_handlers: bool,
+}
+
+impl TestContext {
+    fn new() -> Self {
+        TestContext::with_settings(MAX_REQUESTS_PER_MINUTE, true)
+    }
+
+    fn with_settings(max_requests_per_minute: u64, enable_writing_handlers: bool) -> Self {
+        let planet_repository = Arc::new(InMemoryPlanetRepository::new());
+        let cache = Arc::new(InMemoryCache::new());
+        let broadcaster = Broadcaster::create();
+        let event_publisher = Arc::new(InMemoryEventPublisher::new(broadcaster.clone()));
+
+        let planet_service = Data::new(PlanetService::new(
+            planet_repository.clone(),
+            cache.clone(),
+            event_publisher.clone(),
+            EventPublishing::Direct,
+        ));
+        let rate_limiting_service = Data::new(RateLimitingService::new(
+            Arc::new(InMemoryRateLimiterStore::new()),
+            max_requests_per_minute,
+        ));
+        let pubsub_listener = tokio::spawn(future::pending::<()>());
+        let health_service = Data::new(HealthService::new(
+            planet_repository,
+            cache.clone(),
+            Arc::new(pubsub_listener),
+        ));
+        let graphql_schema = Data::new(graphql::create_schema(
+            planet_service.clone(),
+            broadcaster.clone(),
+            enable_writing_handlers,
+        ));
+
+        TestContext {
+            cache,
+            event_publisher,
+            broadcaster,
+            planet_service,
+            rate_limiting_service,
+            health_service,
+            graphql_schema,
+            admin_config: Data::new(AdminConfig::default()),
+            enable_writing_handlers,
+        }
+    }
+}
+
+// the type of the initialized service can't be named, so a macro is used instead of a function
+macro_rules! init_app {
+    ($ctx:expr) => {
+        test::init_service(
+            App::new()
+                .wrap(HttpMetrics)
+                .wrap(RequestId)
+                .configure(|cfg| routes::configure(cfg, $ctx.enable_writing_handlers, true))
+                .app_data($ctx.admin_config.clone())
+                .app_data($ctx.planet_service.clone())
+                .app_data($ctx.rate_limiting_service.clone())
+                .app_data($ctx.health_service.clone())
+                .app_data($ctx.broadcaster.clone())
+                .app_data($ctx.graphql_schema.clone()),
+        )
+        .await
+    };
+}
+
+fn request(req: TestRequest, uri: &str) -> TestRequest {
+    req.uri(uri)
+        .peer_addr("127.0.0.1:12345".parse().expect("Invalid address"))
+}
+
+fn earth() -> Value {
+    json!({
+        "name": "Earth",
+        "type": "TerrestrialPlanet",
+        "mean_radius": 6371.0,
+        "satellites": [{ "name": "Moon", "first_spacecraft_landing_date": "1959-09-13" }]
+    })
+}
+
+fn jupiter() -> Value {
+    json!({
+        "name": "Jupiter",
+        "type": "GasGiant",
+        "mean_radius": 69911.0,
+        "satellites": [{ "name": "Io" }, { "name": "Europa" }]
+    })
+}
+
+async fn read_json(res: ServiceResponse) -> Value {
+    serde_json::from_slice(&test::read_body(res).await).expect("Response isn't JSON")
+}
+
+fn header_value(res: &ServiceResponse, name: header::HeaderName) -> String {
+    res.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string()
+}
+
+/// Returns the id of the created planet
+macro_rules! create_planet {
+    ($app:expr, $planet:expr) => {{
+        let req = request(TestRequest::post(), "/v1/planets")
+            .set_json(&$planet)
+            .to_request();
+        let res = test::call_service(&$app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        read_json(res).await["id"]
+            .as_str()
+            .expect("Created planet has no id")
+            .to_string()
+    }};
+}
+
+#[actix_web::test]
+async fn creates_and_gets_planets() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+
+    let earth_id = create_planet!(app, earth());
+    create_planet!(app, jupiter());
+
+    let req = request(TestRequest::get(), "/v1/planets").to_request();
+    let planets = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(planets.as_array().map(Vec::len), Some(2));
+
+    let req = request(TestRequest::get(), "/v1/planets?type=GasGiant").to_request();
+    let planets = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(planets[0]["name"], "Jupiter");
+    assert_eq!(planets.as_array().map(Vec::len), Some(1));
+
+    let req = request(TestRequest::get(), &format!("/v1/planets/{}", earth_id)).to_request();
+    let planet = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(planet["name"], "Earth");
+    assert_eq!(planet["satellites"][0]["name"], "Moon");
+}
+
+#[actix_web::test]
+async fn caches_planet() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let earth_id = create_planet!(app, earth());
+
+    let cache_key = format!("planet:{}", earth_id);
+    assert!(ctx.cache.get(&cache_key).await.unwrap().is_none());
+
+    let req = request(TestRequest::get(), &format!("/v1/planets/{}", earth_id)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert!(ctx.cache.get(&cache_key).await.unwrap().is_some());
+
+    let req = request(TestRequest::get(), "/metrics").to_request();
+    let metrics = test::read_body(test::call_service(&app, req).await).await;
+    let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+    assert!(metrics.contains("cache_misses_total{cache=\"planet\"}"));
+
+    let req = request(TestRequest::put(), &format!("/v1/planets/{}", earth_id))
+        .set_json(&earth())
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    assert!(ctx.cache.get(&cache_key).await.unwrap().is_none());
+}
+
+#[actix_web::test]
+async fn skips_change_events_of_invalid_planets() {
+    let ctx = TestContext::new();
+    let id = ObjectId::new();
+    let planet_key = format!("planet:{}", id);
+    for key in [planet_key.as_str(), "planet_stats"] {
+        ctx.cache
+            .set(key, b"{}".to_vec(), Duration::from_secs(60))
+            .await
+            .unwrap();
+    }
+    let mut events = ctx.broadcaster.lock().unwrap().new_subscriber();
+
+    let event: ChangeStreamEvent<Document> = bson::from_document(doc! {
+        "_id": { "_data": "826500000000000000" },
+        "operationType": "insert",
+        "documentKey": { "_id": id },
+        "fullDocument": { "_id": id, "name": 42 },
+    })
+    .unwrap();
+    change_stream::handle_event(&event, &ctx.planet_service, &ctx.broadcaster)
+        .await
+        .unwrap();
+
+    assert!(ctx.cache.get(&planet_key).await.unwrap().is_none());
+    assert!(ctx.cache.get("planet_stats").await.unwrap().is_none());
+    assert!(events.try_recv().is_err());
+}
+
+#[actix_web::test]
+async fn records_http_metrics_by_route() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let earth_id = create_planet!(app, earth());
+
+    let req = request(TestRequest::get(), &format!("/v1/planets/{}", earth_id)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = request(TestRequest::get(), "/unknown/path").to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    let req = request(TestRequest::get(), "/metrics").to_request();
+    let metrics = test::read_body(test::call_service(&app, req).await).await;
+    let metrics = String::from_utf8(metrics.to_vec()).unwrap();
+    assert!(metrics.contains(
+        "http_requests_total{method=\"GET\",path=\"/v1/planets/{planet_id}\",status=\"200\",version=\"v1\"}"
+    ));
+    assert!(metrics.contains(
+        "http_response_size_bytes_count{method=\"GET\",path=\"/v1/planets/{planet_id}\"}"
+    ));
+    assert!(metrics.contains("http_requests_in_flight"));
+    assert!(!metrics.contains(&earth_id));
+    assert!(!metrics.contains("/unknown/path"));
+}
+
+#[actix_web::test]
+async fn returns_not_found() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+
+    let req = request(TestRequest::get(), "/v1/planets/61c0a2f8d4a8a1b6a8b1c2d3").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    assert_eq!(
+        header_value(&res, header::CONTENT_TYPE),
+        "application/problem+json"
+    );
+    let problem = read_json(res).await;
+    assert_eq!(problem["type"], "about:blank");
+    assert_eq!(problem["title"], "Not Found");
+    assert_eq!(problem["status"], 404);
+    assert!(problem["detail"]
+        .as_str()
+        .unwrap()
+        .contains("Can't find a planet"));
+    assert!(problem["request_id"].is_string());
+
+    let req = request(TestRequest::get(), "/openapi.json").to_request();
+    let openapi = read_json(test::call_service(&app, req).await).await;
+    let schema = &openapi["components"]["schemas"]["ErrorResponse"]["properties"];
+    for property in ["type", "title", "status", "detail", "request_id"] {
+        assert!(schema[property].is_object(), "{}", property);
+    }
+}
+
+#[actix_web::test]
+async fn echoes_request_id() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let request_id_header = header::HeaderName::from_static(REQUEST_ID_HEADER);
+
+    let req = request(TestRequest::get(), "/v1/planets/61c0a2f8d4a8a1b6a8b1c2d3")
+        .insert_header((REQUEST_ID_HEADER, "client-request-1"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(
+        header_value(&res, request_id_header.clone()),
+        "client-request-1"
+    );
+    assert_eq!(read_json(res).await["request_id"], "client-request-1");
+
+    // an id that can't be safely logged is replaced with a generated one
+    let req = request(TestRequest::get(), "/v1/planets")
+        .insert_header((REQUEST_ID_HEADER, "bad id"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    let request_id = header_value(&res, request_id_header);
+    assert_eq!(request_id.len(), 24);
+    assert_ne!(request_id, "bad id");
+}
+
+#[actix_web::test]
+async fn streams_planets() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    create_planet!(app, earth());
+    create_planet!(app, jupiter());
+
+    let req = request(TestRequest::get(), "/v1/planets")
+        .insert_header((header::ACCEPT, "application/x-ndjson"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(
+        header_value(&res, header::CONTENT_TYPE),
+        "application/x-ndjson"
+    );
+    let body = test::read_body(res).await;
+    assert_eq!(
+        body.split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .count(),
+        2
+    );
+
+    let req = request(TestRequest::get(), "/v1/planets?stream=true").to_request();
+    let planets = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(planets.as_array().map(Vec::len), Some(2));
+}
+
+#[actix_web::test]
+async fn rejects_planet_with_invalid_id() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+
+    let mut planet = earth();
+    planet["id"] = json!("nope");
+    let req = request(TestRequest::post(), "/v1/planets")
+        .set_json(&planet)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(
+        read_json(res).await["detail"],
+        "Invalid id of a planet: nope"
+    );
+
+    let req = request(TestRequest::post(), "/v1/planets")
+        .insert_header((header::CONTENT_TYPE, "text/csv"))
+        .set_payload("id,name,type,mean_radius,satellites\nnope,Earth,TerrestrialPlanet,6371,\n")
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_web::test]
+async fn updates_planet() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let earth_id = create_planet!(app, earth());
+
+    let mut planet = earth();
+    planet["mean_radius"] = json!(6378.0);
+    let req = request(TestRequest::put(), &format!("/v1/planets/{}", earth_id))
+        .set_json(&planet)
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(read_json(res).await["mean_radius"], 6378.0);
+}
+
+#[actix_web::test]
+async fn rejects_duplicate_names_of_planets() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let earth_id = create_planet!(app, earth());
+    let jupiter_id = create_planet!(app, jupiter());
+
+    let req = request(TestRequest::put(), &format!("/v1/planets/{}", jupiter_id))
+        .set_json(&earth())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CONFLICT);
+    assert_eq!(
+        read_json(res).await["detail"],
+        "A planet with the same name already exists"
+    );
+
+    let req = request(TestRequest::post(), "/v1/planets")
+        .set_json(&earth())
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::CONFLICT
+    );
+
+    // a deleted planet doesn't hold its name
+    let req = request(TestRequest::delete(), &format!("/v1/planets/{}", earth_id)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    create_planet!(app, earth());
+}
+
+#[actix_web::test]
+async fn deletes_and_restores_planet() {
+    let mut ctx = TestContext::new();
+    ctx.admin_config = Data::new(AdminConfig {
+        username: String::from("admin"),
+        password: String::from("secret"),
+        ..AdminConfig::default()
+    });
+    let app = init_app!(ctx);
+    let earth_id = create_planet!(app, earth());
+    let planet_uri = format!("/v1/planets/{}", earth_id);
+
+    let req = request(TestRequest::delete(), &planet_uri).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = request(TestRequest::get(), &planet_uri).to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::NOT_FOUND
+    );
+
+    // deleted planets are visible to admins only
+    for uri in [
+        format!("{}?include_deleted=true", planet_uri),
+        format!("/v2/planets/{}?include_deleted=true", earth_id),
+        String::from("/v1/planets?include_deleted=true&stream=true"),
+        String::from("/v2/planets?include_deleted=true"),
+    ] {
+        let req = request(TestRequest::get(), &uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED, "{}", uri);
+    }
+    let req = request(TestRequest::post(), "/graphql")
+        .set_json(&json!({ "query": "{ planets(filter: { includeDeleted: true }) { name } }" }))
+        .to_request();
+    let res = read_json(test::call_service(&app, req).await).await;
+    assert!(res["data"].is_null());
+    assert!(res["errors"][0]["message"].is_string());
+
+    // "admin:secret"
+    let req = request(
+        TestRequest::get(),
+        &format!("{}?include_deleted=true", planet_uri),
+    )
+    .insert_header((header::AUTHORIZATION, "Basic YWRtaW46c2VjcmV0"))
+    .to_request();
+    let planet = read_json(test::call_service(&app, req).await).await;
+    assert!(planet["deleted_at"].is_string());
+
+    let req = request(TestRequest::post(), &format!("{}/restore", planet_uri)).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = request(TestRequest::get(), &planet_uri).to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+}
+
+#[actix_web::test]
+async fn records_history_and_reverts_planet() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let earth_id = create_planet!(app, earth());
+    let planet_uri = format!("/v1/planets/{}", earth_id);
+
+    let mut planet = earth();
+    planet["name"] = json!("Terra");
+    let req = request(TestRequest::put(), &planet_uri)
+        .insert_header(("X-Actor", "tester"))
+        .set_json(&planet)
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = request(TestRequest::get(), &format!("{}/history", planet_uri)).to_request();
+    let revisions = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(revisions.as_array().map(Vec::len), Some(2));
+    assert_eq!(revisions[1]["operation"], "Update");
+    assert_eq!(revisions[1]["actor"], "tester");
+    assert_eq!(revisions[1]["diff"]["name"]["after"], "Terra");
+
+    let req = request(TestRequest::post(), &format!("{}/revert/1", planet_uri)).to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(read_json(res).await["name"], "Earth");
+}
+
+#[actix_web::test]
+async fn upserts_planets_in_bulk() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    create_planet!(app, earth());
+
+    let req = request(TestRequest::post(), "/v1/planets:bulk")
+        .set_json(&json!([earth(), jupiter(), { "name": "Pluto" }]))
+        .to_request();
+    let result = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(result["created"], 1);
+    assert_eq!(result["updated"], 1);
+    assert_eq!(result["failed"], 1);
+}
+
+#[actix_web::test]
+async fn exports_planets() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let earth_id = create_planet!(app, earth());
+
+    let req = request(TestRequest::get(), "/v1/planets/export?format=csv").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(header_value(&res, header::CONTENT_TYPE), "text/csv");
+    let body = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(body.starts_with("id,name,type,mean_radius,satellites\n"));
+    assert!(body.contains(",Earth,TerrestrialPlanet,"));
+
+    // a planet is written back from CSV without losing landing dates
+    let planet_uri = format!("/v1/planets/{}", earth_id);
+    let req = request(TestRequest::get(), &planet_uri)
+        .insert_header((header::ACCEPT, "text/*"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(header_value(&res, header::CONTENT_TYPE), "text/csv");
+    let csv = test::read_body(res).await;
+    assert!(String::from_utf8_lossy(&csv).contains("Moon:1959-09-13"));
+    let req = request(TestRequest::put(), &planet_uri)
+        .insert_header((header::CONTENT_TYPE, "text/csv"))
+        .set_payload(csv)
+        .to_request();
+    let planet = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(
+        planet["satellites"][0]["first_spacecraft_landing_date"],
+        "1959-09-13"
+    );
+
+    let req = request(TestRequest::get(), "/v1/planets/export").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(
+        header_value(&res, header::CONTENT_TYPE),
+        "application/x-ndjson"
+    );
+}
+
+#[actix_web::test]
+async fn calculates_planet_stats() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    create_planet!(app, earth());
+    create_planet!(app, jupiter());
+
+    let req = request(TestRequest::get(), "/v1/planets/stats").to_request();
+    let stats = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(stats["count_by_type"]["GasGiant"], 1);
+    assert_eq!(stats["count_by_type"]["TerrestrialPlanet"], 1);
+    assert_eq!(stats["total_satellites"], 3);
+    assert_eq!(stats["earliest_landing_date"], "1959-09-13");
+}
+
+#[actix_web::test]
+async fn compares_planets() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    create_planet!(app, earth());
+    let jupiter_id = create_planet!(app, jupiter());
+
+    let req = request(
+        TestRequest::get(),
+        &format!("/v1/planets/compare?ids={}", jupiter_id),
+    )
+    .to_request();
+    let comparison = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(comparison["reference"]["name"], "Earth");
+    assert_eq!(comparison["planets"][0]["name"], "Jupiter");
+
+    let req = request(
+        TestRequest::get(),
+        &format!(
+            "/v1/planets/compare?ids={}&units=earth_relative",
+            jupiter_id
+        ),
+    )
+    .to_request();
+    let comparison = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(
+        comparison["reference"]["mean_radius"]["unit"],
+        "earth_radius"
+    );
+
+    let req = request(TestRequest::get(), "/v1/planets/compare?ids=").to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+
+    // CSV is always in kilometers
+    let req = request(TestRequest::get(), "/v1/planets?units=imperial")
+        .insert_header((header::ACCEPT, "text/csv"))
+        .to_request();
+    assert_eq!(
+        test::call_service(&app, req).await.status(),
+        StatusCode::BAD_REQUEST
+    );
+}
+
+#[actix_web::test]
+async fn gets_image_of_planet() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let earth_id = create_planet!(app, earth());
+
+    let req = request(
+        TestRequest::get(),
+        &format!("/v1/planets/{}/image", earth_id),
+    )
+    .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(header_value(&res, header::CONTENT_TYPE), "image/png");
+    assert!(!test::read_body(res).await.is_empty());
+}
+
+#[actix_web::test]
+async fn serves_api_v2() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+
+    let req = request(TestRequest::post(), "/v2/planets")
+        .set_json(&earth())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::CREATED);
+    let location = header_value(&res, header::LOCATION);
+    let planet = read_json(res).await;
+    assert_eq!(planet["data"]["links"]["self"], location.as_str());
+    assert_eq!(planet["data"]["satellites_count"], 1);
+    let history_link = planet["data"]["links"]["history"].as_str().unwrap();
+    assert_eq!(history_link, format!("{}/history", location));
+    let req = request(TestRequest::get(), history_link).to_request();
+    let history = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(history["meta"]["count"], 1);
+
+    let req = request(TestRequest::put(), &location)
+        .set_json(&jupiter())
+        .to_request();
+    let planet = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(planet["data"]["name"], "Jupiter");
+
+    let req = request(TestRequest::get(), &location).to_request();
+    let planet = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(planet["data"]["name"], "Jupiter");
+
+    let req = request(TestRequest::get(), "/v2/planets").to_request();
+    let planets = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(planets["meta"]["count"], 1);
+}
+
+#[actix_web::test]
+async fn marks_unversioned_paths_as_deprecated() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+
+    let req = request(TestRequest::get(), "/planets").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(header_value(&res, "deprecation".parse().unwrap()), "true");
+    assert_eq!(
+        header_value(&res, header::LINK),
+        "</v1/planets>; rel=\"successor-version\""
+    );
+}
+
+#[actix_web::test]
+async fn disables_writing_handlers() {
+    let ctx = TestContext::with_settings(MAX_REQUESTS_PER_MINUTE, false);
+    let app = init_app!(ctx);
+
+    let req = request(TestRequest::post(), "/v1/planets")
+        .set_json(&earth())
+        .to_request();
+    assert!(test::call_service(&app, req)
+        .await
+        .status()
+        .is_client_error());
+
+    let req = request(TestRequest::post(), "/graphql")
+        .set_json(&json!({ "query": "mutation { deletePlanet(id: \"1\") }" }))
+        .to_request();
+    let response = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(
+        response["errors"][0]["message"],
+        "Writing handlers are disabled"
+    );
+}
+
+#[actix_web::test]
+async fn limits_request_rate() {
+    let ctx = TestContext::with_settings(2, true);
+    let app = init_app!(ctx);
+
+    for _ in 0..2 {
+        let req = request(TestRequest::get(), "/v1/planets").to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    let req = request(TestRequest::get(), "/v1/planets").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(!header_value(&res, header::RETRY_AFTER).is_empty());
+}
+
+#[actix_web::test]
+async fn sends_events_about_created_planets() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+
+    let req = request(TestRequest::get(), "/events").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(
+        header_value(&res, header::CONTENT_TYPE),
+        "text/event-stream"
+    );
+    let mut body = Box::pin(res.into_body());
+
+    assert_eq!(next_chunk(&mut body).await, "data: Connected\n\n");
+    create_planet!(app, earth());
+    assert!(next_chunk(&mut body)
+        .await
+        .starts_with("data: Planet created:"));
+}
+
+#[actix_web::test]
+async fn propagates_trace_context_of_planet_events() {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let tracer_provider = TracerProvider::builder().build();
+    let _subscriber_guard = tracing::subscriber::set_default(
+        tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer("test"))),
+    );
+
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let req = request(TestRequest::get(), "/events").to_request();
+    let mut body = Box::pin(test::call_service(&app, req).await.into_body());
+    assert_eq!(next_chunk(&mut body).await, "data: Connected\n\n");
+    let mut subscriber = ctx.broadcaster.lock().unwrap().new_subscriber();
+
+    async { create_planet!(app, earth()) }
+        .instrument(info_span!("HTTP request"))
+        .await;
+
+    let payloads = ctx.event_publisher.published_payloads();
+    let message: Value = serde_json::from_str(&payloads[0]).unwrap();
+    assert!(message["trace_context"]["traceparent"].is_string());
+
+    // the trace context is only used to continue the trace, it isn't sent to clients
+    let event = next_chunk(&mut body).await;
+    assert!(event.starts_with("data: Planet created:"));
+    assert!(!event.contains("traceparent"));
+    let message = subscriber.recv().await.expect("Subscriber is closed");
+    assert!(!message.contains("traceparent"));
+}
+
+async fn next_chunk<B>(body: &mut Pin<Box<B>>) -> String
+where
+    B: MessageBody,
+    B::Error: std::fmt::Debug,
+{
+    let chunk: Bytes = future::poll_fn(|cx| body.as_mut().poll_next(cx))
+        .await
+        .expect("Stream is finished")
+        .expect("Can't read a chunk");
+    String::from_utf8(chunk.to_vec()).expect("Chunk isn't UTF-8")
+}
+
+#[actix_web::test]
+async fn serves_graphql() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+
+    let req = request(TestRequest::post(), "/graphql")
+        .set_json(&json!({
+            "query": "mutation { createPlanet(planet: { name: \"Jupiter\", type: GAS_GIANT, meanRadius: 69911 }) { id } }"
+        }))
+        .to_request();
+    let response = read_json(test::call_service(&app, req).await).await;
+    let jupiter_id = response["data"]["createPlanet"]["id"]
+        .as_str()
+        .expect("Created planet has no id")
+        .to_string();
+
+    let req = request(TestRequest::post(), "/graphql")
+        .set_json(&json!({
+            "query": "query($id: ID!) { planets { name } planet(id: $id) { name type meanRadius } }",
+            "variables": { "id": jupiter_id }
+        }))
+        .to_request();
+    let response = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(response["data"]["planets"][0]["name"], "Jupiter");
+    assert_eq!(response["data"]["planet"]["type"], "GAS_GIANT");
+
+    let req = request(TestRequest::post(), "/graphql")
+        .set_json(&json!({
+            "query": "mutation { createPlanet(planet: { name: \"Saturn\", type: GAS_GIANT, meanRadius: 9.14, meanRadiusUnit: EARTH_RADIUS }) { meanRadius } }"
+        }))
+        .to_request();
+    let response = read_json(test::call_service(&app, req).await).await;
+    let mean_radius = response["data"]["createPlanet"]["meanRadius"]
+        .as_f64()
+        .expect("Created planet has no mean radius");
+    assert!((mean_radius - 58232.0).abs() < 10.0);
+
+    let req = request(TestRequest::get(), "/graphql").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(header_value(&res, header::CONTENT_TYPE), "text/html");
+}
+
+#[actix_web::test]
+async fn checks_health() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+
+    let req = request(TestRequest::get(), "/health/live").to_request();
+    let health = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(health["status"], "up");
+
+    mongodb_redis::metrics::REDIS_PUBSUB_LISTENER_UP.set(1);
+    let req = request(TestRequest::get(), "/health/ready").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::OK);
+    let health = read_json(res).await;
+    assert_eq!(health["dependencies"]["mongodb"]["status"], "up");
+    assert_eq!(health["dependencies"]["redis"]["status"], "up");
+}
+
+#[actix_web::test]
+async fn serves_static_content_and_metrics() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+
+    for (uri, content_type) in [
+        ("/", "text/html"),
+        ("/openapi", "text/html"),
+        ("/openapi.json", "application/json"),
+        ("/metrics", "text/plain"),
+    ] {
+        let req = request(TestRequest::get(), uri).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK, "{}", uri);
+        assert_eq!(header_value(&res, header::CONTENT_TYPE), content_type);
+    }
+
+    let req = request(TestRequest::get(), "/openapi.json").to_request();
+    let openapi = read_json(test::call_service(&app, req).await).await;
+    assert!(openapi["paths"]["/v1/planets"].is_object());
+    let planet_path = &openapi["paths"]["/v1/planets/{planet_id}"];
+    assert!(planet_path["delete"]["responses"]["200"].is_object());
+    assert!(planet_path["put"]["requestBody"]["content"]["text/csv"].is_object());
+}
+
+#[actix_web::test]
+async fn protects_metrics() {
+    let mut ctx = TestContext::new();
+    ctx.admin_config = Data::new(AdminConfig {
+        username: String::from("admin"),
+        password: String::from("secret"),
+        allowed_ips: vec!["127.0.0.1".parse().unwrap()],
+        ..AdminConfig::default()
+    });
+    let app = init_app!(ctx);
+
+    let req = request(TestRequest::get(), "/metrics").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        header_value(&res, header::WWW_AUTHENTICATE),
+        "Basic realm=\"admin\""
+    );
+
+    // "admin:wrong"
+    let req = request(TestRequest::get(), "/metrics")
+        .insert_header((header::AUTHORIZATION, "Basic YWRtaW46d3Jvbmc="))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+
+    // "admin:secret"
+    let req = request(TestRequest::get(), "/metrics")
+        .insert_header((header::AUTHORIZATION, "Basic YWRtaW46c2VjcmV0"))
+        .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+
+    let req = TestRequest::get()
+        .uri("/metrics")
+        .peer_addr("10.0.0.1:12345".parse().unwrap())
+        .insert_header((header::AUTHORIZATION, "Basic YWRtaW46c2VjcmV0"))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+}
+
+#[actix_web::test]
+async fn manages_cache_and_rate_limits() {
+    let mut ctx = TestContext::new();
+    let app = init_app!(ctx);
+    let req = request(TestRequest::delete(), "/admin/cache").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+    ctx.admin_config = Data::new(AdminConfig {
+        allowed_ips: vec!["127.0.0.1".parse().unwrap()],
+        ..AdminConfig::default()
+    });
+    let app = init_app!(ctx);
+    let earth_id = create_planet!(app, earth());
+    let jupiter_id = create_planet!(app, jupiter());
+    for id in [&earth_id, &jupiter_id] {
+        let req = request(TestRequest::get(), &format!("/v1/planets/{}", id)).to_request();
+        assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    }
+
+    let req = request(
+        TestRequest::delete(),
+        &format!("/admin/cache/planets/{}", earth_id),
+    )
+    .to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let earth_key = format!("planet:{}", earth_id);
+    assert!(ctx.cache.get(&earth_key).await.unwrap().is_none());
+
+    let req = request(TestRequest::delete(), "/admin/cache?prefix=rate_limit").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let req = request(TestRequest::delete(), "/admin/cache?prefix=planet:").to_request();
+    let res = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(res["deleted"], 1);
+    let jupiter_key = format!("planet:{}", jupiter_id);
+    assert!(ctx.cache.get(&jupiter_key).await.unwrap().is_none());
+
+    let req = request(TestRequest::get(), "/v1/planets").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = request(TestRequest::get(), "/admin/rate-limits/127.0.0.1").to_request();
+    let rate_limit = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(
+        rate_limit,
+        json!({ "client": "127.0.0.1", "requests": 1, "limit": MAX_REQUESTS_PER_MINUTE })
+    );
+
+    let req = request(TestRequest::delete(), "/admin/rate-limits/127.0.0.1").to_request();
+    assert_eq!(test::call_service(&app, req).await.status(), StatusCode::OK);
+    let req = request(TestRequest::get(), "/admin/rate-limits/127.0.0.1").to_request();
+    let rate_limit = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(rate_limit["requests"], 0);
+
+    let req = request(TestRequest::get(), "/admin/rate-limits/not-an-ip").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+    let req = request(TestRequest::get(), "/events").to_request();
+    let _events = test::call_service(&app, req).await;
+    let req = request(TestRequest::get(), "/admin/sse-clients").to_request();
+    let clients = read_json(test::call_service(&app, req).await).await;
+    assert_eq!(clients["clients"][0]["address"], "127.0.0.1");
+    assert_eq!(clients["graphql_subscribers"], 0);
+}
+
+#[actix_web::test]
+async fn serves_metrics_in_openmetrics_format() {
+    let ctx = TestContext::new();
+    let app = init_app!(ctx);
+    create_planet!(app, earth());
+
+    let req = request(TestRequest::get(), "/metrics")
+        .insert_header((
+            header::ACCEPT,
+            "application/openmetrics-text;version=1.0.0,text/plain;version=0.0.4;q=0.5",
+        ))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(
+        header_value(&res, header::CONTENT_TYPE),
+        "application/openmetrics-text; version=1.0.0; charset=utf-8"
+    );
+
+    let metrics = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    assert!(metrics.contains("# TYPE http_requests counter\n"));
+    assert!(metrics.contains("http_requests_total{"));
+    assert!(metrics.contains("# TYPE http_response_time_seconds histogram\n"));
+    assert!(metrics.contains("le=\"+Inf\"}"));
+    assert!(metrics.contains("le=\"1.0\"}"));
+    assert!(metrics.ends_with("# EOF\n"));
+
+    // an exemplar is kept if the request is traced
+    mongodb_redis::metrics::observe_http_response_time(
+        "GET",
+        "/exemplar",
+        "200",
+        0.0009,
+        Some(String::from("4bf92f3577b34da6a3ce929d0e0e4736")),
+    );
+    let metrics = mongodb_redis::openmetrics::encode(&prometheus::gather());
+    assert!(metrics.contains(
+        "http_response_time_seconds_bucket{method=\"GET\",path=\"/exemplar\",status=\"200\",le=\"0.0009\"} 1 # {trace_id=\"4bf92f3577b34da6a3ce929d0e0e4736\"} 0.0009 "
+    ));
+}